@@ -0,0 +1,256 @@
+/* Copyright 2017 Outscale SAS
+ *
+ * This file is part of RPG - Remote PacketGraph.
+ *
+ * Pg is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 3 as published
+ * by the Free Software Foundation.
+ *
+ * Packetgraph is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Packetgraph.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+// Settings of antispoof bricks. They are kept in the brick parameters so a
+// rebuilt or restored brick gets them back. packetgraph cannot change the MAC
+// address of an antispoof brick: the brick is built again.
+
+use rocket::{State, Route};
+use rocket_contrib::JSON;
+use super::*;
+use error::{ApiError, ApiResult};
+use metrics::{Metrics, Tracked};
+use state;
+
+#[derive(Deserialize)]
+struct AntispoofMac {
+    mac: String,
+}
+
+#[derive(Deserialize)]
+struct AntispoofIp {
+    ip: String,
+}
+
+#[derive(Deserialize)]
+struct AntispoofChecks {
+    arp: Option<bool>,
+    ndp: Option<bool>,
+}
+
+pub(crate) fn check(spec: &AntispoofCreation) -> ApiResult<()> {
+    side_parse(spec.side.as_str())?;
+    mac_parse(spec.mac.as_str())?;
+    for ip in spec.ipv4.iter() {
+        ipv4_parse(ip)?;
+    }
+    for ip in spec.ipv6.iter() {
+        ipv6_parse(ip)?;
+    }
+    Ok(())
+}
+
+/// Create an antispoof brick with its checks and allowed addresses.
+pub(crate) fn build(spec: &AntispoofCreation) -> ApiResult<SysBrick> {
+    check(spec)?;
+    let side = side_parse(spec.side.as_str())?;
+    let mac = mac_parse(spec.mac.as_str())?;
+    let brick = match sys::antispoof_new(spec.name.as_str(), side, mac) {
+        Ok(b) => b,
+        Err(e) => return Err(ApiError::PgError(format!("cannot create antispoof: {}", e))),
+    };
+    sys::antispoof_arp(&brick, spec.arp);
+    for ip in spec.ipv4.iter() {
+        sys::antispoof_ipv4_add(&brick, ipv4_parse(ip)?).map_err(ApiError::PgError)?;
+    }
+    sys::antispoof_ndp(&brick, spec.ndp).map_err(ApiError::PgError)?;
+    for ip in spec.ipv6.iter() {
+        sys::antispoof_ipv6_add(&brick, ipv6_parse(ip)?).map_err(ApiError::PgError)?;
+    }
+    Ok(brick)
+}
+
+fn settings(g: &RpgGraph, brick_name: &str) -> ApiResult<AntispoofCreation> {
+    match g.specs.get(brick_name) {
+        Some(&BrickCreation::Antispoof(ref a)) => Ok(a.clone()),
+        Some(_) => Err(ApiError::InvalidArgument(format!("brick {} is not an antispoof", brick_name))),
+        None => Err(ApiError::NotFound(format!("brick {} not found", brick_name))),
+    }
+}
+
+// Change settings of an antispoof brick with `change`, which applies them on
+// the brick. Settings are only kept if it succeeds.
+fn settings_change<F>(graphs: &GraphMap,
+                      graph_name: String,
+                      brick_name: String,
+                      change: F)
+                      -> ApiResult<AntispoofCreation>
+    where F: FnOnce(&SysBrick, &mut AntispoofCreation) -> ApiResult<()> + Send + 'static
+{
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        let mut spec = settings(g, &brick_name)?;
+        match g.sys.get(&brick_name) {
+            Some(b) => change(b, &mut spec)?,
+            None => return Err(ApiError::NotFound(format!("brick {} not found", brick_name))),
+        }
+        g.specs.insert(brick_name, BrickCreation::Antispoof(spec.clone()));
+        Ok(spec)
+    })
+}
+
+#[get("/graph/<graph_name>/brick/<brick_name>/antispoof")]
+fn antispoof_get(graphs: State<GraphMap>,
+                 metrics: State<Metrics>,
+                 graph_name: String,
+                 brick_name: String)
+                 -> Tracked<ApiResult<JSON<AntispoofCreation>>> {
+    metrics.track("antispoof_get", || {
+        graph_find(&graphs, &graph_name)?.exec(move |g| settings(g, &brick_name).map(JSON))
+    })
+}
+
+#[put("/graph/<graph_name>/brick/<brick_name>/antispoof/mac", format = "application/json", data = "<mac>")]
+fn antispoof_mac_put(graphs: State<GraphMap>,
+                     metrics: State<Metrics>,
+                     graph_name: String,
+                     brick_name: String,
+                     mac: JSON<AntispoofMac>)
+                     -> Tracked<ApiResult<JSON<AntispoofCreation>>> {
+    metrics.track("antispoof_mac_put", || {
+        let ret = mac_change(&graphs, graph_name, brick_name, mac.into_inner().mac);
+        state::commit(&graphs, ret.map(JSON))
+    })
+}
+
+// Build the brick again with another MAC address, keeping its links.
+fn mac_change(graphs: &GraphMap,
+              graph_name: String,
+              brick_name: String,
+              mac: String)
+              -> ApiResult<AntispoofCreation> {
+    mac_parse(mac.as_str())?;
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        let mut spec = settings(g, &brick_name)?;
+        spec.mac = mac;
+        brick_rebuild(g, &brick_name, BrickCreation::Antispoof(spec.clone()))?;
+        Ok(spec)
+    })
+}
+
+#[post("/graph/<graph_name>/brick/<brick_name>/antispoof/ipv4", format = "application/json", data = "<ip>")]
+fn antispoof_ipv4_add(graphs: State<GraphMap>,
+                      metrics: State<Metrics>,
+                      graph_name: String,
+                      brick_name: String,
+                      ip: JSON<AntispoofIp>)
+                      -> Tracked<ApiResult<JSON<AntispoofCreation>>> {
+    metrics.track("antispoof_ipv4_add", || {
+        let ret = ipv4_parse(ip.ip.as_str()).and_then(|ip| {
+            settings_change(&graphs, graph_name, brick_name, move |b, spec| {
+                let ip_str = format!("{}", ip);
+                if spec.ipv4.contains(&ip_str) {
+                    return Err(ApiError::Conflict(format!("address {} already exists", ip_str)));
+                }
+                sys::antispoof_ipv4_add(b, ip).map_err(ApiError::PgError)?;
+                spec.ipv4.push(ip_str);
+                Ok(())
+            })
+        });
+        state::commit(&graphs, ret.map(JSON))
+    })
+}
+
+#[delete("/graph/<graph_name>/brick/<brick_name>/antispoof/ipv4")]
+fn antispoof_ipv4_flush(graphs: State<GraphMap>,
+                        metrics: State<Metrics>,
+                        graph_name: String,
+                        brick_name: String)
+                        -> Tracked<ApiResult<JSON<AntispoofCreation>>> {
+    metrics.track("antispoof_ipv4_flush", || {
+        let ret = settings_change(&graphs, graph_name, brick_name, |b, spec| {
+            sys::antispoof_ipv4_flush(b);
+            spec.ipv4.clear();
+            Ok(())
+        });
+        state::commit(&graphs, ret.map(JSON))
+    })
+}
+
+#[post("/graph/<graph_name>/brick/<brick_name>/antispoof/ipv6", format = "application/json", data = "<ip>")]
+fn antispoof_ipv6_add(graphs: State<GraphMap>,
+                      metrics: State<Metrics>,
+                      graph_name: String,
+                      brick_name: String,
+                      ip: JSON<AntispoofIp>)
+                      -> Tracked<ApiResult<JSON<AntispoofCreation>>> {
+    metrics.track("antispoof_ipv6_add", || {
+        let ret = ipv6_parse(ip.ip.as_str()).and_then(|ip| {
+            settings_change(&graphs, graph_name, brick_name, move |b, spec| {
+                let ip_str = format!("{}", ip);
+                if spec.ipv6.contains(&ip_str) {
+                    return Err(ApiError::Conflict(format!("address {} already exists", ip_str)));
+                }
+                sys::antispoof_ipv6_add(b, ip).map_err(ApiError::PgError)?;
+                spec.ipv6.push(ip_str);
+                Ok(())
+            })
+        });
+        state::commit(&graphs, ret.map(JSON))
+    })
+}
+
+#[delete("/graph/<graph_name>/brick/<brick_name>/antispoof/ipv6")]
+fn antispoof_ipv6_flush(graphs: State<GraphMap>,
+                        metrics: State<Metrics>,
+                        graph_name: String,
+                        brick_name: String)
+                        -> Tracked<ApiResult<JSON<AntispoofCreation>>> {
+    metrics.track("antispoof_ipv6_flush", || {
+        let ret = settings_change(&graphs, graph_name, brick_name, |b, spec| {
+            sys::antispoof_ipv6_flush(b);
+            spec.ipv6.clear();
+            Ok(())
+        });
+        state::commit(&graphs, ret.map(JSON))
+    })
+}
+
+#[put("/graph/<graph_name>/brick/<brick_name>/antispoof/checks",
+     format = "application/json",
+     data = "<checks>")]
+fn antispoof_checks_put(graphs: State<GraphMap>,
+                        metrics: State<Metrics>,
+                        graph_name: String,
+                        brick_name: String,
+                        checks: JSON<AntispoofChecks>)
+                        -> Tracked<ApiResult<JSON<AntispoofCreation>>> {
+    metrics.track("antispoof_checks_put", || {
+        let checks = checks.into_inner();
+        let ret = settings_change(&graphs, graph_name, brick_name, move |b, spec| {
+            if let Some(ndp) = checks.ndp {
+                sys::antispoof_ndp(b, ndp).map_err(ApiError::PgError)?;
+                spec.ndp = ndp;
+            }
+            if let Some(arp) = checks.arp {
+                sys::antispoof_arp(b, arp);
+                spec.arp = arp;
+            }
+            Ok(())
+        });
+        state::commit(&graphs, ret.map(JSON))
+    })
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![antispoof_get,
+            antispoof_mac_put,
+            antispoof_ipv4_add,
+            antispoof_ipv4_flush,
+            antispoof_ipv6_add,
+            antispoof_ipv6_flush,
+            antispoof_checks_put]
+}
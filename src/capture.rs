@@ -0,0 +1,631 @@
+/* Copyright 2017 Outscale SAS
+ *
+ * This file is part of RPG - Remote PacketGraph.
+ *
+ * Pg is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 3 as published
+ * by the Free Software Foundation.
+ *
+ * Packetgraph is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Packetgraph.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+// Packet captures. A capture brick writing pcap is inserted in a link, or
+// linked on a free side of a brick, and a thread reads what it writes: packets
+// matching the filter are kept in the capture file, or given to a stream,
+// until the packet count or the duration is reached. Stopping a capture
+// destroys its brick and links again the bricks it was inserted between.
+// Captures are not saved.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use rocket::{State, Route};
+use rocket::http::ContentType;
+use rocket::response::content::Content;
+use rocket_contrib::{JSON, Value};
+use super::*;
+use error::{ApiError, ApiResult};
+use metrics::{Metrics, Tracked};
+use state;
+use sys::BpfFilter;
+
+const PCAP_HEADER_LEN: usize = 24;
+const PCAP_RECORD_LEN: usize = 16;
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+/// Largest packet accepted from a capture brick.
+const PACKET_MAX_LEN: usize = 262_144;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CaptureCreation {
+    pub name: String,
+    // Either both bricks of a link, or a brick and one of its sides.
+    #[serde(default)]
+    pub west: Option<String>,
+    #[serde(default)]
+    pub east: Option<String>,
+    #[serde(default)]
+    pub brick: Option<String>,
+    #[serde(default)]
+    pub side: Option<String>,
+    #[serde(default)]
+    pub filter: Option<String>,
+    #[serde(default)]
+    pub max_packets: Option<u64>,
+    // Seconds.
+    #[serde(default)]
+    pub duration: Option<u64>,
+}
+
+/// Packet read from a capture brick.
+pub struct Packet<'a> {
+    pub ts_sec: u32,
+    pub ts_usec: u32,
+    /// Length of the packet, data may be truncated.
+    pub len: u32,
+    pub data: &'a [u8],
+}
+
+/// Where a capture gives packets matching its filter.
+pub enum CaptureOutput {
+    /// pcap file.
+    File(File),
+    Packets(Box<FnMut(&Packet) -> Result<(), String> + Send>),
+}
+
+#[derive(Default)]
+struct CaptureProgress {
+    packets: u64,
+    // Limits are reached, packets are not written anymore.
+    done: bool,
+    error: Option<String>,
+}
+
+pub struct Capture {
+    spec: CaptureCreation,
+    // Capture file, None for streams.
+    path: Option<String>,
+    // Link the capture brick was inserted in.
+    link: Option<LinkCreation>,
+    deadline: Option<Instant>,
+    running: bool,
+    progress: Arc<Mutex<CaptureProgress>>,
+}
+
+impl Capture {
+    fn state(&self) -> &'static str {
+        let expired = self.deadline.map_or(false, |d| Instant::now() >= d);
+        match (self.running, self.progress.lock().unwrap().done || expired) {
+            (false, _) => "stopped",
+            (true, true) => "done",
+            (true, false) => "running",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CaptureDescription {
+    name: String,
+    state: String,
+    packets: u64,
+    error: Option<String>,
+    path: Option<String>,
+    west: Option<String>,
+    east: Option<String>,
+    brick: Option<String>,
+    side: Option<String>,
+    filter: Option<String>,
+    max_packets: Option<u64>,
+    duration: Option<u64>,
+}
+
+impl CaptureDescription {
+    fn new(c: &Capture) -> CaptureDescription {
+        let state = String::from(c.state());
+        let progress = c.progress.lock().unwrap();
+        CaptureDescription {
+            name: c.spec.name.clone(),
+            state: state,
+            packets: progress.packets,
+            error: progress.error.clone(),
+            path: c.path.clone(),
+            west: c.spec.west.clone(),
+            east: c.spec.east.clone(),
+            brick: c.spec.brick.clone(),
+            side: c.spec.side.clone(),
+            filter: c.spec.filter.clone(),
+            max_packets: c.spec.max_packets,
+            duration: c.spec.duration,
+        }
+    }
+}
+
+// Where a capture brick goes.
+enum Placement {
+    Link(LinkCreation),
+    Side(String, Side),
+}
+
+// Directory of capture files.
+fn capture_dir() -> String {
+    let dir = match config::active() {
+        Some(c) => c.get_str("capture_dir").ok().map(String::from),
+        None => None,
+    };
+    dir.unwrap_or_else(|| String::from("/var/lib/rpg/captures"))
+}
+
+fn running(g: &RpgGraph, brick_name: &str) -> bool {
+    g.captures.get(brick_name).map_or(false, |c| c.running)
+}
+
+/// Refuse to remove or rebuild a capture brick or a brick linked to one.
+pub(crate) fn brick_check(g: &RpgGraph, brick_name: &str) -> ApiResult<()> {
+    if running(g, brick_name) {
+        return Err(ApiError::Conflict(format!("brick {} is a running capture", brick_name)));
+    }
+    for l in g.links.iter() {
+        let capture = match (l.west == brick_name, l.east == brick_name) {
+            (true, _) => &l.east,
+            (_, true) => &l.west,
+            _ => continue,
+        };
+        if running(g, capture) {
+            return Err(ApiError::Conflict(format!("brick {} is captured by capture {}", brick_name, capture)));
+        }
+    }
+    Ok(())
+}
+
+/// Refuse to link or unlink a capture brick outside of the capture API.
+pub(crate) fn link_check(g: &RpgGraph, west: &str, east: &str) -> ApiResult<()> {
+    for brick in [west, east].iter() {
+        if running(g, brick) {
+            return Err(ApiError::Conflict(format!("brick {} is a running capture", brick)));
+        }
+    }
+    Ok(())
+}
+
+/// Links to save: captures are left out and the links they were inserted in
+/// are given back.
+pub(crate) fn saved_links(g: &RpgGraph) -> Vec<LinkCreation> {
+    let mut links: Vec<LinkCreation> = g.links
+        .iter()
+        .filter(|l| !running(g, &l.west) && !running(g, &l.east))
+        .cloned()
+        .collect();
+    links.extend(g.captures.values().filter(|c| c.running).filter_map(|c| c.link.clone()));
+    links
+}
+
+// Place a capture on a link, or on a side of a brick: in its link if it has
+// one on this side, on a free port otherwise.
+fn placement(g: &RpgGraph, spec: &CaptureCreation) -> ApiResult<Placement> {
+    match (&spec.west, &spec.east, &spec.brick, &spec.side) {
+        (&Some(ref west), &Some(ref east), &None, &None) => {
+            link_check(g, west, east)?;
+            vtep::link_check(g, west, east)?;
+            match g.links.iter().find(|l| &l.west == west && &l.east == east) {
+                Some(l) => Ok(Placement::Link(l.clone())),
+                None => Err(ApiError::NotFound(format!("no link between {} and {}", west, east))),
+            }
+        }
+        (&None, &None, &Some(ref brick), &Some(ref side)) => {
+            let side = side_parse(side.as_str())?;
+            if !g.brick_exists(brick) {
+                return Err(ApiError::NotFound(format!("brick {} not found", brick)));
+            }
+            let links: Vec<&LinkCreation> = g.links
+                .iter()
+                .filter(|l| match side {
+                    Side::West => &l.east == brick,
+                    _ => &l.west == brick,
+                })
+                .collect();
+            match links.len() {
+                0 => {
+                    link_check(g, brick, brick)?;
+                    Ok(Placement::Side(brick.clone(), side))
+                }
+                1 => {
+                    link_check(g, &links[0].west, &links[0].east)?;
+                    vtep::link_check(g, &links[0].west, &links[0].east)?;
+                    Ok(Placement::Link(links[0].clone()))
+                }
+                _ => {
+                    Err(ApiError::InvalidArgument(format!("brick {} has several links on this side, give west and east \
+                                                           bricks of one",
+                                                          brick)))
+                }
+            }
+        }
+        _ => {
+            Err(ApiError::InvalidArgument(String::from("give either west and east bricks of a link or a brick and its \
+                                                        side")))
+        }
+    }
+}
+
+// Link capture brick `name`, which must exist, and give the link it replaces.
+fn capture_insert(g: &mut RpgGraph, name: &str, placement: Placement) -> ApiResult<Option<LinkCreation>> {
+    let link = |west: &str, east: &str| {
+        LinkCreation {
+            west: String::from(west),
+            east: String::from(east),
+            west_port: None,
+            east_port: None,
+        }
+    };
+    match placement {
+        Placement::Side(brick, Side::West) => link_add(g, link(name, brick.as_str())).map(|_| None),
+        Placement::Side(brick, _) => link_add(g, link(brick.as_str(), name)).map(|_| None),
+        Placement::Link(l) => {
+            bricks_unlink(g, &l.west, &l.east)?;
+            let pos = g.links.iter().position(|k| k.west == l.west && k.east == l.east);
+            if let Some(pos) = pos {
+                g.links.remove(pos);
+            }
+            let ret = link_add(g, link(l.west.as_str(), name)).and_then(|_| link_add(g, link(name, l.east.as_str())));
+            match ret {
+                Ok(()) => Ok(Some(l)),
+                Err(e) => {
+                    capture_remove(g, name, Some(l))?;
+                    Err(e)
+                }
+            }
+        }
+    }
+}
+
+// Destroy capture brick `name` and link again the bricks it was inserted
+// between.
+fn capture_remove(g: &mut RpgGraph, name: &str, link: Option<LinkCreation>) -> ApiResult<()> {
+    g.sys.remove(name);
+    g.links.retain(|l| l.west != name && l.east != name);
+    match link {
+        Some(mut l) => {
+            l.west_port = None;
+            l.east_port = None;
+            link_add(g, l)
+        }
+        None => Ok(()),
+    }
+}
+
+fn u32_get(b: &[u8], little_endian: bool) -> u32 {
+    let (b0, b1, b2, b3) = (b[0] as u32, b[1] as u32, b[2] as u32, b[3] as u32);
+    match little_endian {
+        true => b0 | b1 << 8 | b2 << 16 | b3 << 24,
+        false => b0 << 24 | b1 << 16 | b2 << 8 | b3,
+    }
+}
+
+// Fill `buf`, false once the capture brick has closed the pipe.
+fn read_full(input: &mut File, buf: &mut [u8]) -> Result<bool, String> {
+    match input.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(format!("cannot read capture: {}", e)),
+    }
+}
+
+// Give packets matching the filter from the pcap stream of a capture brick to
+// the output while limits are not reached, the output is closed afterwards.
+fn packets_copy(input: &mut File,
+                output: CaptureOutput,
+                filter: Option<BpfFilter>,
+                max_packets: Option<u64>,
+                deadline: Option<Instant>,
+                progress: &Mutex<CaptureProgress>)
+                -> Result<(), String> {
+    let mut output = Some(output);
+    let mut header = [0u8; PCAP_HEADER_LEN];
+    if !read_full(input, &mut header)? {
+        return Ok(());
+    }
+    let little_endian = match (u32_get(&header, true), u32_get(&header, false)) {
+        (PCAP_MAGIC, _) => true,
+        (_, PCAP_MAGIC) => false,
+        _ => return Err(String::from("capture brick does not write pcap")),
+    };
+    if let Some(CaptureOutput::File(ref mut f)) = output {
+        f.write_all(&header).map_err(|e| format!("cannot write capture: {}", e))?;
+    }
+    let mut record = [0u8; PCAP_RECORD_LEN];
+    let mut data = Vec::new();
+    loop {
+        if !read_full(input, &mut record)? {
+            return Ok(());
+        }
+        let caplen = u32_get(&record[8..], little_endian) as usize;
+        let len = u32_get(&record[12..], little_endian);
+        if caplen > PACKET_MAX_LEN {
+            return Err(format!("packet of {} bytes is too large", caplen));
+        }
+        data.resize(caplen, 0);
+        if !read_full(input, &mut data)? {
+            return Ok(());
+        }
+        let mut p = progress.lock().unwrap();
+        if output.is_none() {
+            continue;
+        }
+        if filter.as_ref().map_or(true, |f| f.matches(&data, len)) {
+            match output {
+                Some(CaptureOutput::File(ref mut f)) => {
+                    f.write_all(&record).map_err(|e| format!("cannot write capture: {}", e))?;
+                    f.write_all(&data).map_err(|e| format!("cannot write capture: {}", e))?;
+                }
+                Some(CaptureOutput::Packets(ref mut give)) => {
+                    let packet = Packet {
+                        ts_sec: u32_get(&record, little_endian),
+                        ts_usec: u32_get(&record[4..], little_endian),
+                        len: len,
+                        data: &data,
+                    };
+                    (**give)(&packet)?;
+                }
+                None => {}
+            }
+            p.packets += 1;
+        }
+        if max_packets.map_or(false, |m| p.packets >= m) || deadline.map_or(false, |d| Instant::now() >= d) {
+            p.done = true;
+            output = None;
+        }
+    }
+}
+
+// Thread reading a capture brick until it is destroyed. The pipe is drained
+// after an error so the poll thread never blocks on it.
+fn capture_read(mut input: File,
+                output: CaptureOutput,
+                filter: Option<BpfFilter>,
+                max_packets: Option<u64>,
+                deadline: Option<Instant>,
+                progress: Arc<Mutex<CaptureProgress>>) {
+    if let Err(e) = packets_copy(&mut input, output, filter, max_packets, deadline, &progress) {
+        let mut p = progress.lock().unwrap();
+        p.error = Some(e);
+        p.done = true;
+    }
+    let mut buf = [0u8; 4096];
+    while let Ok(n) = input.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+    }
+}
+
+fn capture_find<'a>(g: &'a RpgGraph, name: &str) -> ApiResult<&'a Capture> {
+    match g.captures.get(name) {
+        Some(c) => Ok(c),
+        None => Err(ApiError::NotFound(format!("capture {} not found", name))),
+    }
+}
+
+/// Start a capture giving packets to the output `open` gives, which is only
+/// opened once the capture is known to be possible. `path` is the file of
+/// the output, removed if the capture cannot start.
+pub(crate) fn capture_start<F>(g: &mut RpgGraph,
+                               spec: CaptureCreation,
+                               path: Option<String>,
+                               open: F)
+                               -> ApiResult<CaptureDescription>
+    where F: FnOnce() -> ApiResult<CaptureOutput>
+{
+    let name = spec.name.clone();
+    if g.captures.contains_key(&name) {
+        return Err(ApiError::Conflict(format!("capture {} already exists", name)));
+    }
+    brick_check_free(g, &name)?;
+    let placement = placement(g, &spec)?;
+    let filter = match spec.filter {
+        Some(ref f) => Some(BpfFilter::new(f.as_str()).map_err(ApiError::InvalidArgument)?),
+        None => None,
+    };
+    let output = open()?;
+    let remove_file = || {
+        if let Some(ref p) = path {
+            let _ = fs::remove_file(p);
+        }
+    };
+    let (brick, input) = match sys::capture_new(&name) {
+        Ok(b) => b,
+        Err(e) => {
+            remove_file();
+            return Err(ApiError::PgError(format!("cannot create capture: {}", e)));
+        }
+    };
+    let deadline = spec.duration.map(|d| Instant::now() + Duration::from_secs(d));
+    let progress = Arc::new(Mutex::new(CaptureProgress::default()));
+    let reader_progress = progress.clone();
+    let max_packets = spec.max_packets;
+    thread::spawn(move || capture_read(input, output, filter, max_packets, deadline, reader_progress));
+    g.sys.insert(name.clone(), brick);
+    // Known as a capture before being linked so its ports are found.
+    g.captures.insert(name.clone(),
+                      Capture {
+                          spec: spec,
+                          path: path.clone(),
+                          link: None,
+                          deadline: deadline,
+                          running: true,
+                          progress: progress,
+                      });
+    let link = match capture_insert(g, &name, placement) {
+        Ok(l) => l,
+        Err(e) => {
+            capture_remove(g, &name, None)?;
+            g.captures.remove(&name);
+            remove_file();
+            return Err(e);
+        }
+    };
+    match g.captures.get_mut(&name) {
+        Some(c) => {
+            c.link = link;
+            Ok(CaptureDescription::new(c))
+        }
+        None => Err(ApiError::NotFound(format!("capture {} not found", name))),
+    }
+}
+
+// Graph and capture names are used in paths of capture files.
+fn file_name_check(kind: &str, name: &str) -> ApiResult<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(ApiError::InvalidArgument(format!("{} name {:?} cannot be used as a file name", kind, name)));
+    }
+    Ok(())
+}
+
+// Create a new capture file, an existing file or link is never opened.
+fn capture_file(path: &str) -> ApiResult<File> {
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir).map_err(|e| ApiError::PgError(format!("cannot create {}: {}", dir.display(), e)))?;
+    }
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(f) => Ok(f),
+        Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {
+            Err(ApiError::Conflict(format!("capture file {} already exists", path)))
+        }
+        Err(e) => Err(ApiError::PgError(format!("cannot create {}: {}", path, e))),
+    }
+}
+
+fn capture_create(graphs: &GraphMap, graph_name: String, spec: CaptureCreation) -> ApiResult<CaptureDescription> {
+    file_name_check("graph", &graph_name)?;
+    file_name_check("capture", &spec.name)?;
+    let path = format!("{}/{}/{}.pcap", capture_dir(), graph_name, spec.name);
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        let file = path.clone();
+        capture_start(g, spec, Some(path), move || capture_file(&file).map(CaptureOutput::File))
+    })
+}
+
+// Destroy the brick of a capture, its file is kept.
+fn capture_stop(g: &mut RpgGraph, name: &str) -> ApiResult<()> {
+    let link = match g.captures.get(name) {
+        Some(c) if c.running => c.link.clone(),
+        Some(_) => return Ok(()),
+        None => return Err(ApiError::NotFound(format!("capture {} not found", name))),
+    };
+    vtep::unbound_check(g, name)?;
+    if let Some(c) = g.captures.get_mut(name) {
+        c.running = false;
+    }
+    capture_remove(g, name, link)
+}
+
+/// Stop a capture and forget it, with its file.
+pub(crate) fn capture_end(g: &mut RpgGraph, name: &str) -> ApiResult<()> {
+    capture_stop(g, name)?;
+    if let Some(c) = g.captures.remove(name) {
+        if let Some(p) = c.path {
+            let _ = fs::remove_file(p);
+        }
+    }
+    Ok(())
+}
+
+#[post("/graph/<graph_name>/capture", format = "application/json", data = "<capture>")]
+fn capture_new(graphs: State<GraphMap>,
+               metrics: State<Metrics>,
+               graph_name: String,
+               capture: JSON<CaptureCreation>)
+               -> Tracked<ApiResult<JSON<CaptureDescription>>> {
+    metrics.track("capture_new", || {
+        let ret = capture_create(&graphs, graph_name, capture.into_inner());
+        state::commit(&graphs, ret.map(JSON))
+    })
+}
+
+#[get("/graph/<graph_name>/capture")]
+fn captures_get(graphs: State<GraphMap>,
+                metrics: State<Metrics>,
+                graph_name: String)
+                -> Tracked<ApiResult<JSON<Vec<CaptureDescription>>>> {
+    metrics.track("captures_get", || {
+        graph_find(&graphs, &graph_name)?.exec(|g| {
+            let mut captures: Vec<CaptureDescription> = g.captures.values().map(CaptureDescription::new).collect();
+            captures.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(JSON(captures))
+        })
+    })
+}
+
+#[get("/graph/<graph_name>/capture/<name>")]
+fn capture_get(graphs: State<GraphMap>,
+               metrics: State<Metrics>,
+               graph_name: String,
+               name: String)
+               -> Tracked<ApiResult<JSON<CaptureDescription>>> {
+    metrics.track("capture_get", || {
+        graph_find(&graphs, &graph_name)?.exec(move |g| {
+            capture_find(g, &name).map(|c| JSON(CaptureDescription::new(c)))
+        })
+    })
+}
+
+#[get("/graph/<graph_name>/capture/<name>/pcap")]
+fn capture_download(graphs: State<GraphMap>,
+                    metrics: State<Metrics>,
+                    graph_name: String,
+                    name: String)
+                    -> Tracked<ApiResult<Content<File>>> {
+    metrics.track("capture_download", || {
+        let path = graph_find(&graphs, &graph_name)?.exec(move |g| {
+            match capture_find(g, &name)?.path {
+                Some(ref p) => Ok(p.clone()),
+                None => Err(ApiError::InvalidArgument(format!("capture {} has no file", name))),
+            }
+        })?;
+        match File::open(&path) {
+            Ok(f) => Ok(Content(ContentType::new("application", "vnd.tcpdump.pcap"), f)),
+            Err(e) => Err(ApiError::NotFound(format!("cannot open {}: {}", path, e))),
+        }
+    })
+}
+
+#[post("/graph/<graph_name>/capture/<name>/stop")]
+fn capture_stop_post(graphs: State<GraphMap>,
+                     metrics: State<Metrics>,
+                     graph_name: String,
+                     name: String)
+                     -> Tracked<ApiResult<JSON<CaptureDescription>>> {
+    metrics.track("capture_stop", || {
+        let ret = graph_find(&graphs, &graph_name)?.exec(move |g| {
+            capture_stop(g, &name)?;
+            capture_find(g, &name).map(|c| JSON(CaptureDescription::new(c)))
+        });
+        state::commit(&graphs, ret)
+    })
+}
+
+#[delete("/graph/<graph_name>/capture/<name>")]
+fn capture_delete(graphs: State<GraphMap>,
+                  metrics: State<Metrics>,
+                  graph_name: String,
+                  name: String)
+                  -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("capture_delete", || {
+        let ret = graph_find(&graphs, &graph_name)?.exec(move |g| {
+            capture_end(g, &name)?;
+            Ok(success())
+        });
+        state::commit(&graphs, ret)
+    })
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![capture_new, captures_get, capture_get, capture_download, capture_stop_post, capture_delete]
+}
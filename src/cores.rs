@@ -0,0 +1,200 @@
+/* Copyright 2017 Outscale SAS
+ *
+ * This file is part of RPG - Remote PacketGraph.
+ *
+ * Pg is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 3 as published
+ * by the Free Software Foundation.
+ *
+ * Packetgraph is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Packetgraph.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+// Poll threads can be pinned on a CPU core, either chosen at graph creation or
+// taken from the `cores` pool of Rocket.toml. When PG_DPDK_OPTS gives a list
+// of lcores, graphs can only be pinned on those cores.
+
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Once, ONCE_INIT};
+use libc;
+use rocket::config;
+use error::{ApiError, ApiResult};
+use runtime::GraphRuntime;
+
+// Parse a core list like "0,2-4".
+fn list_parse(list: &str) -> Option<Vec<usize>> {
+    let mut cores = Vec::new();
+    for item in list.split(',') {
+        let mut bounds = item.trim().splitn(2, '-');
+        let first = match bounds.next().map(|b| b.parse::<usize>()) {
+            Some(Ok(c)) => c,
+            _ => return None,
+        };
+        let last = match bounds.next().map(|b| b.parse::<usize>()) {
+            None => first,
+            Some(Ok(c)) if c >= first => c,
+            _ => return None,
+        };
+        cores.extend(first..last + 1);
+    }
+    Some(cores)
+}
+
+// Parse a core mask like "0x5".
+fn mask_parse(mask: &str) -> Option<Vec<usize>> {
+    let mask = mask.trim_left_matches("0x").trim_left_matches("0X");
+    match u64::from_str_radix(mask, 16) {
+        Ok(m) => Some((0..64).filter(|c| m & (1 << c) != 0).collect()),
+        Err(_) => None,
+    }
+}
+
+// Parse DPDK --lcores syntax like "0,1@2,(3-4)@(5,6)" and keep the CPUs
+// lcores run on.
+fn lcores_list_parse(list: &str) -> Option<Vec<usize>> {
+    let mut cores = Vec::new();
+    let mut item = String::new();
+    let mut depth = 0;
+    for c in list.chars().chain(Some(',')) {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                let cpus = match item.rfind('@') {
+                    Some(at) => item[at + 1..].to_string(),
+                    None => item.clone(),
+                };
+                let cpus = cpus.trim_matches(|c| c == '(' || c == ')');
+                match list_parse(cpus) {
+                    Some(l) => cores.extend(l),
+                    None => return None,
+                }
+                item.clear();
+                continue;
+            }
+            _ => {}
+        }
+        item.push(c);
+    }
+    Some(cores)
+}
+
+/// Cores given to DPDK with -l, --lcores or -c options, None if none is set or
+/// cannot be parsed.
+pub fn dpdk_cores(opts: &str) -> Option<Vec<usize>> {
+    let mut cores = None;
+    let mut args = opts.split_whitespace();
+    while let Some(arg) = args.next() {
+        let (parse, value): (fn(&str) -> Option<Vec<usize>>, Option<&str>) = match arg {
+            "-l" => (list_parse, args.next()),
+            "-c" => (mask_parse, args.next()),
+            "--lcores" => (lcores_list_parse, args.next()),
+            a if a.starts_with("--lcores=") => (lcores_list_parse, Some(&a[9..])),
+            a if a.starts_with("-l") => (list_parse, Some(&a[2..])),
+            a if a.starts_with("-c") => (mask_parse, Some(&a[2..])),
+            _ => continue,
+        };
+        cores = value.and_then(parse);
+    }
+    cores
+}
+
+fn lcores() -> Option<Vec<usize>> {
+    match env::var("PG_DPDK_OPTS") {
+        Ok(opts) => dpdk_cores(opts.as_str()),
+        Err(_) => None,
+    }
+}
+
+static POOL_INIT: Once = ONCE_INIT;
+static mut POOL: Option<Vec<usize>> = None;
+
+/// Parse the `cores` pool of Rocket.toml once at startup, rpg must not start
+/// with a pool it cannot parse.
+pub fn init() -> Result<(), String> {
+    let cores = match config::active() {
+        Some(c) => c.get_str("cores").ok().map(String::from),
+        None => None,
+    };
+    let pool = match cores {
+        Some(c) => {
+            match list_parse(c.as_str()) {
+                Some(p) => Some(p),
+                None => return Err(format!("cannot parse cores \"{}\", use a list like \"2,4-7\"", c)),
+            }
+        }
+        None => None,
+    };
+    POOL_INIT.call_once(|| unsafe { POOL = pool });
+    Ok(())
+}
+
+fn pool() -> Option<Vec<usize>> {
+    // Only written by init() before graphs are created.
+    unsafe { POOL.clone() }
+}
+
+/// Check the core requested for a graph or pick a free one from the pool. The
+/// core of an existing graph named `graph_name` can be taken again as this
+/// graph is being replaced.
+pub fn assign(map: &HashMap<String, Arc<GraphRuntime>>,
+              graph_name: &str,
+              core: Option<usize>)
+              -> ApiResult<Option<usize>> {
+    let used: HashMap<usize, &String> = map.iter()
+        .filter(|&(name, _)| name != graph_name)
+        .filter_map(|(name, g)| g.core.map(|c| (c, name)))
+        .collect();
+    let lcores = lcores();
+    let pool = pool();
+    match core {
+        Some(c) => {
+            if lcores.as_ref().map_or(false, |l| !l.contains(&c)) {
+                return Err(ApiError::InvalidArgument(format!("core {} is not in DPDK lcores", c)));
+            }
+            if pool.as_ref().map_or(false, |p| !p.contains(&c)) {
+                return Err(ApiError::InvalidArgument(format!("core {} is not in cores pool", c)));
+            }
+            match used.get(&c) {
+                Some(name) => Err(ApiError::Conflict(format!("core {} is already used by graph {}", c, name))),
+                None => Ok(Some(c)),
+            }
+        }
+        None => {
+            let pool = match pool {
+                Some(p) => p,
+                None => return Ok(None),
+            };
+            let free = pool.into_iter()
+                .filter(|c| lcores.as_ref().map_or(true, |l| l.contains(c)))
+                .find(|c| !used.contains_key(c));
+            match free {
+                Some(c) => Ok(Some(c)),
+                None => Err(ApiError::Conflict(String::from("no free core left in cores pool"))),
+            }
+        }
+    }
+}
+
+/// Pin the calling thread on a core.
+pub fn pin(core: usize) -> Result<(), String> {
+    if core >= libc::CPU_SETSIZE as usize {
+        return Err(format!("cannot pin graph on core {}: no such core", core));
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(core, &mut set);
+        match libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) {
+            0 => Ok(()),
+            _ => Err(format!("cannot pin graph on core {}: {}", core, io::Error::last_os_error())),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+/* Copyright 2017 Outscale SAS
+ *
+ * This file is part of RPG - Remote PacketGraph.
+ *
+ * Pg is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 3 as published
+ * by the Free Software Foundation.
+ *
+ * Packetgraph is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Packetgraph.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::fmt::Display;
+use rocket::Request;
+use rocket::http::Status;
+use rocket::response::{self, Responder, Response};
+use rocket_contrib::{JSON, Value};
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// Graph or brick does not exist.
+    NotFound(String),
+    /// Graph or brick already exists.
+    Conflict(String),
+    /// Request is malformed or a parameter has a bad value.
+    InvalidArgument(String),
+    /// Packetgraph refused the operation.
+    PgError(String),
+    /// Some rules of a firewall rule set cannot be compiled.
+    InvalidRules(Vec<RuleError>),
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuleError {
+    /// Position of the rule in the rule set.
+    pub index: usize,
+    pub filter: String,
+    pub error: String,
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+impl ApiError {
+    pub fn pg<E: Display>(e: E) -> ApiError {
+        ApiError::PgError(format!("{}", e))
+    }
+
+    fn status(&self) -> Status {
+        match *self {
+            ApiError::NotFound(_) => Status::NotFound,
+            ApiError::Conflict(_) => Status::Conflict,
+            ApiError::InvalidArgument(_) |
+            ApiError::InvalidRules(_) => Status::BadRequest,
+            ApiError::PgError(_) => Status::InternalServerError,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match *self {
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::InvalidArgument(_) |
+            ApiError::InvalidRules(_) => "invalid_argument",
+            ApiError::PgError(_) => "pg_error",
+        }
+    }
+
+    pub fn description(&self) -> &str {
+        match *self {
+            ApiError::NotFound(ref d) |
+            ApiError::Conflict(ref d) |
+            ApiError::InvalidArgument(ref d) |
+            ApiError::PgError(ref d) => d.as_str(),
+            ApiError::InvalidRules(_) => "invalid firewall rules",
+        }
+    }
+}
+
+fn error_body(kind: &str, description: &str) -> JSON<Value> {
+    JSON(json!({
+        "status": "error",
+        "error": kind,
+        "description": description,
+    }))
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond(self) -> response::Result<'r> {
+        let mut body = error_body(self.kind(), self.description());
+        if let ApiError::InvalidRules(ref errors) = self {
+            if let Some(o) = body.0.as_object_mut() {
+                o.insert(String::from("rules"), json!(errors));
+            }
+        }
+        let body = body.respond()?;
+        Response::build_from(body).status(self.status()).ok()
+    }
+}
+
+#[error(400)]
+fn bad_request(_req: &Request) -> JSON<Value> {
+    error_body("invalid_argument", "malformed request")
+}
+
+#[error(404)]
+fn not_found(req: &Request) -> JSON<Value> {
+    error_body("not_found", format!("no route for {}", req.uri()).as_str())
+}
+
+#[error(422)]
+fn unprocessable_entity(_req: &Request) -> JSON<Value> {
+    error_body("invalid_argument", "cannot parse request body")
+}
+
+#[error(500)]
+fn internal_error(_req: &Request) -> JSON<Value> {
+    error_body("internal_error", "internal server error")
+}
+
+pub fn catchers() -> Vec<::rocket::Catcher> {
+    errors![bad_request, not_found, unprocessable_entity, internal_error]
+}
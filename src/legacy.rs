@@ -0,0 +1,275 @@
+/* Copyright 2017 Outscale SAS
+ *
+ * This file is part of RPG - Remote PacketGraph.
+ *
+ * Pg is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 3 as published
+ * by the Free Software Foundation.
+ *
+ * Packetgraph is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Packetgraph.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+// GET routes of the first API version. Those routes modify the graphs and are
+// only mounted when `legacy_routes` is set in Rocket.toml as any crawler or
+// proxy prefetching an URL could create or destroy graphs.
+
+use rocket::{State, Rocket};
+use rocket_contrib::{JSON, Value};
+use super::*;
+use error::ApiResult;
+use metrics::{Metrics, Tracked};
+use state;
+
+#[get("/graph/new?<graph>")]
+fn graph_new(graphs: State<GraphMap>,
+             metrics: State<Metrics>,
+             graph: GraphCreation)
+             -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_graph_new", || {
+        state::commit(&graphs, graph_create(&graphs, graph))
+    })
+}
+
+#[get("/graph/<graph_name>/delete")]
+fn graph_delete(graphs: State<GraphMap>,
+                metrics: State<Metrics>,
+                graph_name: &str)
+                -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_graph_delete", || {
+        state::commit(&graphs, graph_remove(&graphs, graph_name))
+    })
+}
+
+#[get("/graph/<graph_name>/brick/link?<link>")]
+fn link(graphs: State<GraphMap>,
+        metrics: State<Metrics>,
+        graph_name: String,
+        link: LinkCreation)
+        -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_link", || {
+        state::commit(&graphs, link_create(&graphs, graph_name, link))
+    })
+}
+
+#[get("/graph/<graph_name>/brick/unlink?<unlink>")]
+fn unlink_from(graphs: State<GraphMap>,
+               metrics: State<Metrics>,
+               graph_name: String,
+               unlink: LinkDeletion)
+               -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_unlink_from", || {
+        state::commit(&graphs, link_remove(&graphs, graph_name, unlink))
+    })
+}
+
+#[get("/graph/<graph_name>/brick/<brick_name>/unlink")]
+fn unlink(graphs: State<GraphMap>,
+          metrics: State<Metrics>,
+          graph_name: String,
+          brick_name: String)
+          -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_unlink", || {
+        state::commit(&graphs, brick_unlink(&graphs, graph_name, brick_name))
+    })
+}
+
+#[get("/graph/<graph_name>/brick/<brick_name>/delete")]
+fn brick_delete(graphs: State<GraphMap>,
+                metrics: State<Metrics>,
+                graph_name: String,
+                brick_name: String)
+                -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_brick_delete", || {
+        state::commit(&graphs, brick_remove(&graphs, graph_name, brick_name))
+    })
+}
+
+#[get("/graph/<graph_name>/brick/new/nop?<nop>")]
+fn nop_new(graphs: State<GraphMap>,
+           metrics: State<Metrics>,
+           graph_name: String,
+           nop: NopCreation)
+           -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_nop_new", || {
+        state::commit(&graphs, brick_create(&graphs, graph_name, BrickCreation::Nop(nop)))
+    })
+}
+
+#[get("/graph/<graph_name>/brick/new/tap?<tap>")]
+fn tap_new(graphs: State<GraphMap>,
+           metrics: State<Metrics>,
+           graph_name: String,
+           tap: TapCreation)
+           -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_tap_new", || {
+        state::commit(&graphs, brick_create(&graphs, graph_name, BrickCreation::Tap(tap)))
+    })
+}
+
+#[get("/graph/<graph_name>/brick/new/hub?<hub>")]
+fn hub_new(graphs: State<GraphMap>,
+           metrics: State<Metrics>,
+           graph_name: String,
+           hub: HubCreation)
+           -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_hub_new", || {
+        state::commit(&graphs, brick_create(&graphs, graph_name, BrickCreation::Hub(hub)))
+    })
+}
+
+#[get("/graph/<graph_name>/brick/new/switch?<switch>")]
+fn switch_new(graphs: State<GraphMap>,
+              metrics: State<Metrics>,
+              graph_name: String,
+              switch: SwitchCreation)
+              -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_switch_new", || {
+        state::commit(&graphs, brick_create(&graphs, graph_name, BrickCreation::Switch(switch)))
+    })
+}
+
+#[get("/graph/<graph_name>/brick/new/nic?<nic>")]
+fn nic_new(graphs: State<GraphMap>,
+           metrics: State<Metrics>,
+           graph_name: String,
+           nic: NicCreation)
+           -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_nic_new", || {
+        state::commit(&graphs, brick_create(&graphs, graph_name, BrickCreation::Nic(nic)))
+    })
+}
+
+#[get("/graph/<graph_name>/brick/new/firewall?<firewall>")]
+fn firewall_new(graphs: State<GraphMap>,
+                metrics: State<Metrics>,
+                graph_name: String,
+                firewall: FirewallCreation)
+                -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_firewall_new", || {
+        state::commit(&graphs, brick_create(&graphs, graph_name, BrickCreation::Firewall(firewall)))
+    })
+}
+
+#[get("/graph/<graph_name>/brick/new/vhost?<vhost>")]
+fn vhost_new(graphs: State<GraphMap>,
+             metrics: State<Metrics>,
+             graph_name: String,
+             vhost: VhostCreation)
+             -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_vhost_new", || {
+        state::commit(&graphs, brick_create(&graphs, graph_name, BrickCreation::Vhost(vhost)))
+    })
+}
+
+#[get("/graph/<graph_name>/brick/new/vtep?<vtep>")]
+fn vtep_new(graphs: State<GraphMap>,
+            metrics: State<Metrics>,
+            graph_name: String,
+            vtep: VtepCreation)
+            -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_vtep_new", || {
+        state::commit(&graphs, brick_create(&graphs, graph_name, BrickCreation::Vtep(vtep)))
+    })
+}
+
+#[get("/graph/<graph_name>/brick/new/queue?<queue>")]
+fn queue_new(graphs: State<GraphMap>,
+             metrics: State<Metrics>,
+             graph_name: String,
+             queue: QueueCreation)
+             -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_queue_new", || {
+        state::commit(&graphs, brick_create(&graphs, graph_name, BrickCreation::Queue(queue)))
+    })
+}
+
+#[derive(FromForm)]
+struct AntispoofForm {
+    name: String,
+    side: String,
+    mac: String,
+}
+
+#[get("/graph/<graph_name>/brick/new/antispoof?<antispoof>")]
+fn antispoof_new(graphs: State<GraphMap>,
+                 metrics: State<Metrics>,
+                 graph_name: String,
+                 antispoof: AntispoofForm)
+                 -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_antispoof_new", || {
+        let spec = AntispoofCreation {
+            name: antispoof.name,
+            side: antispoof.side,
+            mac: antispoof.mac,
+            arp: false,
+            ndp: false,
+            ipv4: Vec::new(),
+            ipv6: Vec::new(),
+        };
+        state::commit(&graphs, brick_create(&graphs, graph_name, BrickCreation::Antispoof(spec)))
+    })
+}
+
+#[get("/graph/<graph_name>/brick/<brick_name>/firewall/rule?<rule>")]
+fn firewall_rule_add(graphs: State<GraphMap>,
+                     metrics: State<Metrics>,
+                     graph_name: String,
+                     brick_name: String,
+                     rule: FirewallRule)
+                     -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_firewall_rule_add", || {
+        let rule = FirewallRuleEntry::new(rule);
+        let ret = firewall_rule_create(&graphs, graph_name, brick_name, rule).map(|_| success());
+        state::commit(&graphs, ret)
+    })
+}
+
+#[get("/graph/<graph_name>/brick/<brick_name>/firewall/flush")]
+fn firewall_flush(graphs: State<GraphMap>,
+                  metrics: State<Metrics>,
+                  graph_name: String,
+                  brick_name: String)
+                  -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_firewall_flush", || {
+        state::commit(&graphs, firewall_rules_flush(&graphs, graph_name, brick_name))
+    })
+}
+
+#[get("/graph/<graph_name>/brick/<brick_name>/firewall/reload")]
+fn firewall_reload(graphs: State<GraphMap>,
+                   metrics: State<Metrics>,
+                   graph_name: String,
+                   brick_name: String)
+                   -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("legacy_firewall_reload", || {
+        state::commit(&graphs, firewall_apply(&graphs, graph_name, brick_name))
+    })
+}
+
+pub fn mount(rocket: Rocket) -> Rocket {
+    rocket.mount("/", routes![graph_new,
+                              graph_delete,
+                              link,
+                              unlink_from,
+                              unlink,
+                              brick_delete,
+                              nop_new,
+                              tap_new,
+                              hub_new,
+                              switch_new,
+                              nic_new,
+                              firewall_new,
+                              vhost_new,
+                              vtep_new,
+                              antispoof_new,
+                              queue_new,
+                              firewall_rule_add,
+                              firewall_flush,
+                              firewall_reload])
+}
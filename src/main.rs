@@ -25,6 +25,20 @@ extern crate rocket_contrib;
 #[macro_use]
 extern crate serde_derive;
 extern crate pg;
+extern crate libc;
+
+mod antispoof;
+mod capture;
+mod cores;
+mod error;
+mod legacy;
+mod metrics;
+mod queue;
+mod runtime;
+mod state;
+mod stream;
+mod sys;
+mod vtep;
 
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
@@ -33,606 +47,1865 @@ use rocket::{State, Rocket};
 use rocket_contrib::{JSON, Value};
 use rocket::response::content::Content;
 use rocket::http::ContentType;
-use std::thread;
+use rocket::config;
 use std::str::FromStr;
+use std::cmp::max;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+use error::{ApiError, ApiResult, RuleError};
+use metrics::{Metrics, Tracked, PollStats};
+use runtime::{GraphRuntime, RunState};
+use sys::{BpfFilter, BpfInsn, PgBrick, SysBrick};
+use capture::Capture;
+use queue::QueueFriend;
+use vtep::VtepVni;
 
 static API_VERSION: &'static str = "0.1.0";
 
 struct RpgGraph {
     graph: Graph,
-    run: bool,
+    // Bricks pg does not wrap, polled after the pg graph.
+    sys: HashMap<String, SysBrick>,
+    run_state: RunState,
+    core: Option<usize>,
+    // pg does not give back brick parameters, links or firewall rules so rpg
+    // keeps track of them to be able to save and rebuild a graph.
+    specs: HashMap<String, BrickCreation>,
+    links: Vec<LinkCreation>,
+    firewall_rules: HashMap<String, FirewallRules>,
+    // VNIs bound on each vtep.
+    vteps: HashMap<String, Vec<VtepVni>>,
+    // Friend of each friended queue, which may be in another graph.
+    queue_friends: HashMap<String, QueueFriend>,
+    // Packet captures, running ones have a brick in `sys`.
+    captures: HashMap<String, Capture>,
+    poll_stats: PollStats,
 }
 
-type GraphMap = Arc<RwLock<HashMap<String, Arc<RwLock<RpgGraph>>>>>;
+impl RpgGraph {
+    fn new(name: String, core: Option<usize>) -> RpgGraph {
+        RpgGraph {
+            graph: Graph::new(name),
+            sys: HashMap::new(),
+            run_state: RunState::Running,
+            core: core,
+            specs: HashMap::new(),
+            links: Vec::new(),
+            firewall_rules: HashMap::new(),
+            vteps: HashMap::new(),
+            queue_friends: HashMap::new(),
+            captures: HashMap::new(),
+            poll_stats: PollStats::default(),
+        }
+    }
+
+    fn poll(&mut self) {
+        self.graph.poll();
+        for b in self.sys.values_mut() {
+            b.poll();
+        }
+    }
+
+    fn brick_insert(&mut self, name: String, brick: AnyBrick) {
+        match brick {
+            AnyBrick::Pg(b) => {
+                self.graph.bricks.insert(name, b);
+            }
+            AnyBrick::Sys(b) => {
+                self.sys.insert(name, b);
+            }
+        }
+    }
+
+    // Destroy a brick, false if it does not exist.
+    fn brick_destroy(&mut self, name: &str) -> bool {
+        self.graph.bricks.remove(name).is_some() || self.sys.remove(name).is_some()
+    }
+
+    fn brick_exists(&self, name: &str) -> bool {
+        self.graph.bricks.contains_key(name) || self.sys.contains_key(name)
+    }
+
+    fn brick_names(&self) -> Vec<String> {
+        self.graph.bricks.keys().chain(self.sys.keys()).cloned().collect()
+    }
+
+    fn brick_ptr(&self, name: &str) -> Option<*mut PgBrick> {
+        match (self.graph.bricks.get(name), self.sys.get(name)) {
+            (Some(b), _) => Some(sys::pg_ptr(b)),
+            (None, Some(b)) => Some(b.ptr()),
+            (None, None) => None,
+        }
+    }
+
+    fn pkts_count(&mut self, name: &str, side: Side) -> Option<u64> {
+        if let Some(b) = self.graph.bricks.get_mut(name) {
+            return Some(b.pkts_count(side));
+        }
+        self.sys.get(name).map(|b| b.pkts_count(side))
+    }
+}
+
+/// Brick built from its parameters, pg graphs can only hold pg bricks.
+enum AnyBrick {
+    Pg(Brick),
+    Sys(SysBrick),
+}
+
+type GraphMap = Arc<RwLock<HashMap<String, Arc<GraphRuntime>>>>;
 
 #[derive(Serialize)]
 struct BrickDescription {
     name: String,
     type_name: String,
+    west_ports: Option<u32>,
+    east_ports: Option<u32>,
+    west: Vec<String>,
+    east: Vec<String>,
+    ports: Vec<PortDescription>,
+    // Socket of a vhost-user brick.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socket_path: Option<String>,
+    // Settings of an antispoof brick.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    antispoof: Option<AntispoofCreation>,
+    // Friend of a queue brick.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_friend: Option<QueueFriend>,
 }
 
-impl BrickDescription {
-    fn new(brick: &Brick) -> BrickDescription {
-        BrickDescription {
-            name: brick.name(),
-            type_name: String::from(brick.type_str()),
+#[derive(Serialize)]
+struct PortDescription {
+    side: String,
+    port: u32,
+    // Brick linked on this port and its port, None if the port is free.
+    peer: Option<String>,
+    peer_port: Option<u32>,
+}
+
+impl PortDescription {
+    // Describe ports of one side of a brick, ports of bricks without port
+    // count are only listed when used.
+    fn side(g: &RpgGraph, brick: &str, east_side: bool, count: Option<u32>) -> Vec<PortDescription> {
+        let mut peers = Vec::new();
+        for l in g.links.iter() {
+            match east_side {
+                true if l.west == brick => peers.push((l.west_port, l.east.clone(), l.east_port)),
+                false if l.east == brick => peers.push((l.east_port, l.west.clone(), l.west_port)),
+                _ => {}
+            }
         }
+        let side = String::from(if east_side { "east" } else { "west" });
+        let ports: Vec<u32> = match count {
+            Some(n) => (0..n).collect(),
+            None => peers.iter().filter_map(|&(p, _, _)| p).collect(),
+        };
+        ports.into_iter()
+            .map(|port| {
+                let peer = peers.iter().find(|&&(p, _, _)| p == Some(port));
+                PortDescription {
+                    side: side.clone(),
+                    port: port,
+                    peer: peer.map(|&(_, ref b, _)| b.clone()),
+                    peer_port: peer.and_then(|&(_, _, p)| p),
+                }
+            })
+            .collect()
+    }
+}
+
+impl BrickDescription {
+    fn new(g: &RpgGraph, name: &str) -> ApiResult<BrickDescription> {
+        let (type_name, socket_path) = match (g.graph.bricks.get(name), g.sys.get(name)) {
+            (Some(b), _) => (String::from(b.type_str()), None),
+            (None, Some(b)) => (String::from(b.type_str()), b.socket_path()),
+            (None, None) => return Err(ApiError::NotFound(format!("brick {} not found", name))),
+        };
+        let (west_ports, east_ports) = match g.specs.get(name) {
+            Some(spec) => spec.ports(),
+            None => (None, None),
+        };
+        let antispoof = match g.specs.get(name) {
+            Some(&BrickCreation::Antispoof(ref a)) => Some(a.clone()),
+            _ => None,
+        };
+        let mut ports = PortDescription::side(g, name, false, west_ports);
+        ports.extend(PortDescription::side(g, name, true, east_ports));
+        Ok(BrickDescription {
+            west_ports: west_ports,
+            east_ports: east_ports,
+            ports: ports,
+            west: g.links.iter().filter(|l| l.east == name).map(|l| l.west.clone()).collect(),
+            east: g.links.iter().filter(|l| l.west == name).map(|l| l.east.clone()).collect(),
+            name: String::from(name),
+            type_name: type_name,
+            socket_path: socket_path,
+            antispoof: antispoof,
+            queue_friend: g.queue_friends.get(name).cloned(),
+        })
     }
 }
 
 #[derive(Serialize)]
 struct GraphDescription {
     name: String,
+    state: String,
+    core: Option<usize>,
     bricks: Vec<String>,
+    links: Vec<LinkCreation>,
 }
 
 impl GraphDescription {
-    fn new(graph: &Graph) -> GraphDescription {
-        let mut bricks = Vec::new();
-        for name in graph.bricks.keys() {
-            bricks.push(name.clone());
-        }
+    fn new(g: &RpgGraph) -> GraphDescription {
+        let bricks = g.brick_names();
         GraphDescription {
-            name: graph.name.clone(),
+            name: g.graph.name.clone(),
+            state: String::from(g.run_state.name()),
+            core: g.core,
             bricks: bricks,
+            links: g.links.clone(),
         }
     }
 }
 
-fn result<S: Into<String>>(status: bool, description: S) -> JSON<Value> {
-    let d = description.into();
+fn success() -> JSON<Value> {
     JSON(json!({
-        "status": match status {
-            true => "ok",
-            false => "error",
-        },
-        "description": d.as_str(),
+        "status": "ok",
+        "description": "",
     }))
 }
 
+fn graph_find(graphs: &GraphMap, graph_name: &str) -> ApiResult<Arc<GraphRuntime>> {
+    let map = graphs.read().unwrap();
+    match map.get(graph_name) {
+        Some(g) => Ok(g.clone()),
+        None => Err(ApiError::NotFound(format!("graph {} not found", graph_name))),
+    }
+}
+
+fn brick_find<'a>(g: &'a mut RpgGraph, brick_name: &str) -> ApiResult<&'a mut Brick> {
+    match g.graph.bricks.get_mut(brick_name) {
+        Some(b) => Ok(b),
+        None => Err(ApiError::NotFound(format!("brick {} not found", brick_name))),
+    }
+}
+
+fn firewall_find<'a>(g: &'a mut RpgGraph, brick_name: &str) -> ApiResult<&'a mut Firewall> {
+    if g.sys.contains_key(brick_name) {
+        return Err(ApiError::InvalidArgument(format!("brick {} is not a firewall", brick_name)));
+    }
+    match brick_find(g, brick_name)?.firewall() {
+        Some(fw) => Ok(fw),
+        None => Err(ApiError::InvalidArgument(format!("brick {} is not a firewall", brick_name))),
+    }
+}
+
+fn brick_check_free(g: &RpgGraph, brick_name: &str) -> ApiResult<()> {
+    match g.brick_exists(brick_name) {
+        true => Err(ApiError::Conflict(format!("brick {} already exists", brick_name))),
+        false => Ok(()),
+    }
+}
+
+fn side_parse(side: &str) -> ApiResult<Side> {
+    match Side::from_str(side) {
+        Ok(s) => Ok(s),
+        Err(_) => Err(ApiError::InvalidArgument(String::from("choose west or east for side parameter"))),
+    }
+}
+
+fn ipv4_parse(ip: &str) -> ApiResult<Ipv4Addr> {
+    Ipv4Addr::from_str(ip).map_err(|_| ApiError::InvalidArgument(format!("{} is not an IPv4 address", ip)))
+}
+
+fn ipv6_parse(ip: &str) -> ApiResult<Ipv6Addr> {
+    Ipv6Addr::from_str(ip).map_err(|_| ApiError::InvalidArgument(format!("{} is not an IPv6 address", ip)))
+}
+
+fn mac_parse(mac: &str) -> ApiResult<[u8; 6]> {
+    let mut addr = [0u8; 6];
+    let bytes: Vec<&str> = mac.split(':').collect();
+    if bytes.len() != addr.len() {
+        return Err(ApiError::InvalidArgument(format!("{} is not a MAC address", mac)));
+    }
+    for (a, b) in addr.iter_mut().zip(bytes) {
+        *a = match u8::from_str_radix(b, 16) {
+            Ok(v) if b.len() == 2 => v,
+            _ => return Err(ApiError::InvalidArgument(format!("{} is not a MAC address", mac))),
+        };
+    }
+    Ok(addr)
+}
+
 #[derive(Serialize)]
 struct ApiDescription {
     version: String
 }
 
 #[get("/")]
-fn index() -> JSON<ApiDescription> {
-    JSON(ApiDescription{version: String::from(API_VERSION)})
+fn index(metrics: State<Metrics>) -> Tracked<JSON<ApiDescription>> {
+    metrics.track("index", || {
+        JSON(ApiDescription{version: String::from(API_VERSION)})
+    })
 }
 
 #[get("/graph")]
-fn graphs(graphs: State<GraphMap>) -> Option<JSON<Vec<String>>> {
-    let map = graphs.read().unwrap();
-    let mut res = Vec::<String>::new();
-    for (name, _) in map.iter() {
-        res.push(name.clone());
-    }
-    return Some(JSON(res));
+fn graphs(graphs: State<GraphMap>, metrics: State<Metrics>) -> Tracked<JSON<Vec<String>>> {
+    metrics.track("graphs", || {
+        let map = graphs.read().unwrap();
+        let mut res = Vec::<String>::new();
+        for (name, _) in map.iter() {
+            res.push(name.clone());
+        }
+        JSON(res)
+    })
 }
 
-#[derive(FromForm)]
+#[derive(FromForm, Deserialize)]
 struct GraphCreation {
-    name: String
+    name: String,
+    core: Option<usize>,
 }
 
-#[get("/graph/new?<graph>")]
-fn graph_new(graphs: State<GraphMap>, graph: GraphCreation) -> Option<JSON<Value>> {
+#[post("/graph", format = "application/json", data = "<graph>")]
+fn graph_new(graphs: State<GraphMap>,
+             metrics: State<Metrics>,
+             graph: JSON<GraphCreation>)
+             -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("graph_new", || {
+        state::commit(&graphs, graph_create(&graphs, graph.into_inner()))
+    })
+}
+
+fn graph_create(graphs: &GraphMap, graph: GraphCreation) -> ApiResult<JSON<Value>> {
     let mut map = graphs.write().unwrap();
     if map.get(&graph.name).is_some() {
-        return Some(result(false, "graph already exists"));
+        return Err(ApiError::Conflict(format!("graph {} already exists", graph.name)));
     }
-    let new_graph = Arc::new(RwLock::new(RpgGraph {
-                                             graph: Graph::new(graph.name.clone()),
-                                             run: true,
-                                         }));
-    let ng = new_graph.clone();
-    thread::spawn(move || pooler(ng));
-    map.insert(graph.name.clone(), new_graph);
-    return Some(result(true, ""));
+    let core = cores::assign(&map, &graph.name, graph.core)?;
+    let new_graph = GraphRuntime::spawn(RpgGraph::new(graph.name.clone(), core))?;
+    map.insert(graph.name, Arc::new(new_graph));
+    Ok(success())
 }
 
 #[get("/graph/<graph_name>")]
-fn graph_get(graphs: State<GraphMap>, graph_name: String) -> Option<JSON<GraphDescription>> {
-    let map = graphs.read().unwrap();
-    let g = match map.get(&graph_name) {
-        Some(g) => g,
-        None => return None,
-    };
+fn graph_get(graphs: State<GraphMap>,
+             metrics: State<Metrics>,
+             graph_name: String)
+             -> Tracked<ApiResult<JSON<GraphDescription>>> {
+    metrics.track("graph_get", || {
+        let g = graph_find(&graphs, &graph_name)?;
+        Ok(JSON(g.exec(|g| Ok(GraphDescription::new(g)))?))
+    })
+}
 
-    let g = g.read().unwrap();
-    let desc = GraphDescription::new(&g.graph);
-    return Some(JSON(desc));
+#[get("/graph/<graph_name>/export")]
+fn graph_export(graphs: State<GraphMap>,
+                metrics: State<Metrics>,
+                graph_name: String)
+                -> Tracked<ApiResult<JSON<state::GraphState>>> {
+    metrics.track("graph_export", || {
+        let g = graph_find(&graphs, &graph_name)?;
+        Ok(JSON(g.exec(|g| Ok(state::GraphState::new(g)))?))
+    })
 }
 
-#[get("/graph/<graph_name>/delete")]
-fn graph_delete(graphs: State<GraphMap>, graph_name: &str) -> Option<JSON<Value>> {
-    let mut map = graphs.write().unwrap();
-    match map.remove(graph_name) {
-        Some(g) => {
-            let mut g = g.write().unwrap();
-            g.run = false;
-            Some(result(true, ""))
-        }
-        None => None,
-    }
+#[put("/graph/<graph_name>", format = "application/json", data = "<graph>")]
+fn graph_import(graphs: State<GraphMap>,
+                metrics: State<Metrics>,
+                graph_name: String,
+                graph: JSON<state::GraphState>)
+                -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("graph_import", || {
+        let ret = state::import(&graphs, graph_name, graph.into_inner()).map(|_| success());
+        state::commit(&graphs, ret)
+    })
 }
 
-#[get("/graph/<graph_name>/dot")]
-fn dot_get(graphs: State<GraphMap>, graph_name: String) -> Option<String> {
+#[derive(Deserialize)]
+struct Rename {
+    name: String,
+}
+
+#[post("/graph/<graph_name>/rename", format = "application/json", data = "<rename>")]
+fn graph_rename(graphs: State<GraphMap>,
+                metrics: State<Metrics>,
+                graph_name: String,
+                rename: JSON<Rename>)
+                -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("graph_rename", || {
+        state::commit(&graphs, graph_name_change(&graphs, graph_name, rename.into_inner().name))
+    })
+}
+
+fn graph_name_change(graphs: &GraphMap, graph_name: String, new_name: String) -> ApiResult<JSON<Value>> {
     let mut map = graphs.write().unwrap();
-    let g = match map.get_mut(&graph_name) {
+    if map.get(&new_name).is_some() {
+        return Err(ApiError::Conflict(format!("graph {} already exists", new_name)));
+    }
+    let g = match map.remove(&graph_name) {
         Some(g) => g,
-        None => return None,
+        None => return Err(ApiError::NotFound(format!("graph {} not found", graph_name))),
     };
-    let mut g = g.write().unwrap();
-    match g.graph.dot() {
-        Err(_) => Some(String::new()),
-        Ok(s) => Some(s),
+    let name = new_name.clone();
+    let ret = g.exec(move |g| {
+        // Friends of its queues know the graph by its name.
+        queue::graph_check(g)?;
+        g.graph.name = name;
+        Ok(())
+    });
+    if let Err(e) = ret {
+        map.insert(graph_name, g);
+        return Err(e);
     }
+    map.insert(new_name, g);
+    Ok(success())
 }
 
-#[get("/graph/<graph_name>/svg")]
-fn dot_get_svg(graphs: State<GraphMap>, graph_name: String) -> Option<Content<String>> {
+#[delete("/graph/<graph_name>")]
+fn graph_delete(graphs: State<GraphMap>,
+                metrics: State<Metrics>,
+                graph_name: &str)
+                -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("graph_delete", || {
+        state::commit(&graphs, graph_remove(&graphs, graph_name))
+    })
+}
+
+fn graph_remove(graphs: &GraphMap, graph_name: &str) -> ApiResult<JSON<Value>> {
     let mut map = graphs.write().unwrap();
-    let g = match map.get_mut(&graph_name) {
-        Some(g) => g,
-        None => return None,
+    let g = match map.get(graph_name) {
+        Some(g) => g.clone(),
+        None => return Err(ApiError::NotFound(format!("graph {} not found", graph_name))),
     };
-    let mut g = g.write().unwrap();
-    match g.graph.svg() {
-        Err(_) => None,
-        Ok(s) => Some(Content(ContentType::SVG, s)),
+    // A graph whose thread has stopped can still be removed.
+    if let Err(e @ ApiError::Conflict(_)) = g.exec(|g| queue::graph_check(g)) {
+        return Err(e);
     }
+    map.remove(graph_name);
+    g.quit();
+    Ok(success())
+}
+
+fn graph_run_state(graphs: &GraphMap, graph_name: &str, state: RunState) -> ApiResult<JSON<Value>> {
+    graph_find(graphs, graph_name)?.exec(move |g| {
+        g.run_state = state;
+        Ok(())
+    })?;
+    Ok(success())
+}
+
+#[post("/graph/<graph_name>/start")]
+fn graph_start(graphs: State<GraphMap>,
+               metrics: State<Metrics>,
+               graph_name: &str)
+               -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("graph_start", || {
+        graph_run_state(&graphs, graph_name, RunState::Running)
+    })
+}
+
+#[post("/graph/<graph_name>/stop")]
+fn graph_stop(graphs: State<GraphMap>,
+              metrics: State<Metrics>,
+              graph_name: &str)
+              -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("graph_stop", || {
+        graph_run_state(&graphs, graph_name, RunState::Stopped)
+    })
+}
+
+/// Longest pause, one day.
+const PAUSE_MAX_MS: u64 = 86_400_000;
+
+#[derive(Deserialize)]
+struct GraphPause {
+    duration_ms: u64,
+}
+
+#[post("/graph/<graph_name>/pause", format = "application/json", data = "<pause>")]
+fn graph_pause(graphs: State<GraphMap>,
+               metrics: State<Metrics>,
+               graph_name: &str,
+               pause: JSON<GraphPause>)
+               -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("graph_pause", || {
+        if pause.duration_ms > PAUSE_MAX_MS {
+            return Err(ApiError::InvalidArgument(format!("duration_ms cannot exceed {}", PAUSE_MAX_MS)));
+        }
+        let until = Instant::now() + Duration::from_millis(pause.duration_ms);
+        graph_run_state(&graphs, graph_name, RunState::Paused(until))
+    })
+}
+
+#[get("/graph/<graph_name>/dot")]
+fn dot_get(graphs: State<GraphMap>,
+           metrics: State<Metrics>,
+           graph_name: String)
+           -> Tracked<ApiResult<String>> {
+    metrics.track("dot_get", || {
+        let g = graph_find(&graphs, &graph_name)?;
+        match g.exec(|g| Ok(g.graph.dot().ok()))? {
+            None => Ok(String::new()),
+            Some(s) => Ok(s),
+        }
+    })
+}
+
+#[get("/graph/<graph_name>/svg")]
+fn dot_get_svg(graphs: State<GraphMap>,
+               metrics: State<Metrics>,
+               graph_name: String)
+               -> Tracked<ApiResult<Content<String>>> {
+    metrics.track("dot_get_svg", || {
+        let g = graph_find(&graphs, &graph_name)?;
+        let svg = g.exec(|g| g.graph.svg().map_err(ApiError::pg))?;
+        Ok(Content(ContentType::SVG, svg))
+    })
 }
 
 #[get("/graph/<graph_name>/brick/<brick_name>")]
 fn brick_get(graphs: State<GraphMap>,
+             metrics: State<Metrics>,
              graph_name: String,
              brick_name: String)
-             -> Option<JSON<BrickDescription>> {
-    let map = graphs.read().unwrap();
-    let g = match map.get(&graph_name) {
-        Some(g) => g,
-        None => return None,
-    };
-    let g = g.write().unwrap();
-    let b = match g.graph.bricks.get(&brick_name) {
-        Some(b) => b,
-        None => return None,
-    };
-    let desc = BrickDescription::new(b);
-    return Some(JSON(desc));
+             -> Tracked<ApiResult<JSON<BrickDescription>>> {
+    metrics.track("brick_get", || {
+        graph_find(&graphs, &graph_name)?.exec(move |g| {
+            BrickDescription::new(g, &brick_name).map(JSON)
+        })
+    })
 }
 
-#[derive(FromForm)]
-struct LinkCreation {
-    west: String,
-    east: String,
+#[derive(Serialize)]
+struct BrickStats {
+    west_pkts: u64,
+    east_pkts: u64,
+    // Firewalls only, when their drops can be derived.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    west_dropped: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    east_dropped: Option<u64>,
 }
 
-#[get("/graph/<graph_name>/brick/link?<link>")]
-fn link(graphs: State<GraphMap>, graph_name: String, link: LinkCreation) -> Option<JSON<Value>> {
-    let mut map = graphs.write().unwrap();
-    let g = match map.get_mut(&graph_name) {
-        Some(g) => g,
-        None => return None,
-    };
-
-    let mut g = g.write().unwrap();
-    let west = g.graph.bricks.remove(&link.west);
-    let east = g.graph.bricks.remove(&link.east);
-    let mut ret: Option<JSON<Value>> = None;
-
-    if west.is_some() && east.is_some() {
-        let mut w = west.unwrap();
-        let mut e = east.unwrap();
-        ret = match w.link(&mut e) {
-            Ok(()) => Some(result(true, "")),
-            Err(e) => Some(result(false, format!("{}", e))),
+impl BrickStats {
+    fn new(g: &mut RpgGraph, brick_name: &str) -> ApiResult<BrickStats> {
+        let (west, east) = match (g.pkts_count(brick_name, Side::West), g.pkts_count(brick_name, Side::East)) {
+            (Some(west), Some(east)) => (west, east),
+            _ => return Err(ApiError::NotFound(format!("brick {} not found", brick_name))),
+        };
+        let firewall = match g.specs.get(brick_name) {
+            Some(&BrickCreation::Firewall(_)) => true,
+            _ => false,
         };
-        g.graph.bricks.insert(link.west, w);
-        g.graph.bricks.insert(link.east, e);
-    } else if west.is_none() && east.is_none() {
-        ret = Some(result(false, "west and east bricks not found"));
-    } else if west.is_none() {
-        g.graph.bricks.insert(link.east, east.unwrap());
-        ret = Some(result(false, "west brick not found"));
-    } else if east.is_none() {
-        g.graph.bricks.insert(link.west, west.unwrap());
-        ret = Some(result(false, "east brick not found"));
+        let (west_dropped, east_dropped) = match firewall {
+            true => metrics::firewall_drops(g, brick_name),
+            false => (None, None),
+        };
+        Ok(BrickStats {
+            west_pkts: west,
+            east_pkts: east,
+            west_dropped: west_dropped,
+            east_dropped: east_dropped,
+        })
     }
-    return ret;
 }
 
-#[derive(FromForm)]
-struct LinkDeletion {
+#[derive(Serialize)]
+struct GraphStats {
+    name: String,
+    west_pkts: u64,
+    east_pkts: u64,
+    bricks: HashMap<String, BrickStats>,
+}
+
+#[get("/graph/<graph_name>/brick/<brick_name>/stats")]
+fn brick_stats(graphs: State<GraphMap>,
+               metrics: State<Metrics>,
+               graph_name: String,
+               brick_name: String)
+               -> Tracked<ApiResult<JSON<BrickStats>>> {
+    metrics.track("brick_stats", || {
+        graph_find(&graphs, &graph_name)?.exec(move |g| {
+            Ok(JSON(BrickStats::new(g, &brick_name)?))
+        })
+    })
+}
+
+#[get("/graph/<graph_name>/stats")]
+fn graph_stats(graphs: State<GraphMap>,
+               metrics: State<Metrics>,
+               graph_name: String)
+               -> Tracked<ApiResult<JSON<GraphStats>>> {
+    metrics.track("graph_stats", || {
+        graph_find(&graphs, &graph_name)?.exec(move |g| {
+            let mut stats = GraphStats {
+                name: graph_name,
+                west_pkts: 0,
+                east_pkts: 0,
+                bricks: HashMap::new(),
+            };
+            for name in g.brick_names() {
+                let s = BrickStats::new(g, &name)?;
+                stats.west_pkts += s.west_pkts;
+                stats.east_pkts += s.east_pkts;
+                stats.bricks.insert(name, s);
+            }
+            Ok(JSON(stats))
+        })
+    })
+}
+
+#[derive(FromForm, Serialize, Deserialize, Clone)]
+struct LinkCreation {
     west: String,
     east: String,
+    // Port of the west brick (on its east side) and port of the east brick
+    // (on its west side), the first free port is used if not set.
+    #[serde(default)]
+    west_port: Option<u32>,
+    #[serde(default)]
+    east_port: Option<u32>,
 }
 
-#[get("/graph/<graph_name>/brick/unlink?<unlink>")]
-fn unlink_from(graphs: State<GraphMap>, graph_name: String, unlink: LinkDeletion) -> Option<JSON<Value>> {
-    let mut map = graphs.write().unwrap();
-    let g = match map.get_mut(&graph_name) {
-        Some(g) => g,
-        None => return None,
-    };
-
-    let mut g = g.write().unwrap();
-    let west = g.graph.bricks.remove(&unlink.west);
-    let east = g.graph.bricks.remove(&unlink.east);
-    let mut ret: Option<JSON<Value>> = None;
-
-    if west.is_some() && east.is_some() {
-        let mut w = west.unwrap();
-        let mut e = east.unwrap();
-        ret = match w.unlink_from(&mut e) {
-            Ok(()) => Some(result(true, "")),
-            Err(e) => Some(result(false, format!("{}", e))),
-        };
-        g.graph.bricks.insert(unlink.west, w);
-        g.graph.bricks.insert(unlink.east, e);
-    } else if west.is_none() && east.is_none() {
-        ret = Some(result(false, "west and east bricks not found"));
-    } else if west.is_none() {
-        g.graph.bricks.insert(unlink.east, east.unwrap());
-        ret = Some(result(false, "west brick not found"));
-    } else if east.is_none() {
-        g.graph.bricks.insert(unlink.west, west.unwrap());
-        ret = Some(result(false, "east brick not found"));
+// Ports used on one side of a brick.
+fn ports_used(g: &RpgGraph, brick: &str, east_side: bool) -> Vec<u32> {
+    let mut used = Vec::new();
+    for l in g.links.iter() {
+        match east_side {
+            true if l.west == brick => used.extend(l.west_port),
+            false if l.east == brick => used.extend(l.east_port),
+            _ => {}
+        }
     }
-    return ret;
+    used
 }
 
-#[get("/graph/<graph_name>/brick/<brick_name>/unlink")]
-fn unlink(graphs: State<GraphMap>,
-        graph_name: String,
-        brick_name: String)
-    -> Option<JSON<Value>> {
-    let mut map = graphs.write().unwrap();
-    let g = match map.get_mut(&graph_name) {
-        Some(g) => g,
-        None => return None,
+// Check the port requested on one side of a brick for a new link. Packetgraph
+// always links on the first free port so this is the only one which can be
+// requested.
+fn port_pick(g: &RpgGraph, brick: &str, east_side: bool, port: Option<u32>) -> ApiResult<u32> {
+    let side = if east_side { "east" } else { "west" };
+    let (west_ports, east_ports) = match g.specs.get(brick) {
+        Some(spec) => spec.ports(),
+        // Capture bricks have a port on each side.
+        None if g.captures.contains_key(brick) => (Some(1), Some(1)),
+        None => (None, None),
     };
-
-    let mut g = g.write().unwrap();
-    let mut b = match g.graph.bricks.get_mut(&brick_name) {
-        Some(b) => b,
-        None => return None,
+    let (count, used) = match (east_side, west_ports, east_ports) {
+        (false, Some(n), _) | (true, _, Some(n)) => (n, ports_used(g, brick, east_side)),
+        // A single port shared by both sides.
+        _ => {
+            let mut used = ports_used(g, brick, false);
+            used.extend(ports_used(g, brick, true));
+            (1, used)
+        }
     };
-    b.unlink();
-    Some(result(true, ""))
+    let free = (0..count).find(|p| !used.contains(p));
+    match (port, free) {
+        (Some(p), _) if p >= count => {
+            Err(ApiError::InvalidArgument(format!("brick {} has no port {} on {} side", brick, p, side)))
+        }
+        (Some(p), _) if used.contains(&p) => {
+            Err(ApiError::Conflict(format!("port {} on {} side of brick {} is already used", p, side, brick)))
+        }
+        (Some(p), Some(f)) if p != f => {
+            Err(ApiError::InvalidArgument(format!("port {} on {} side of brick {} cannot be used before port {}",
+                                                  p, side, brick, f)))
+        }
+        (_, Some(f)) => Ok(f),
+        (_, None) => Err(ApiError::Conflict(format!("no free port on {} side of brick {}", side, brick))),
+    }
 }
 
+#[post("/graph/<graph_name>/link", format = "application/json", data = "<link>")]
+fn link(graphs: State<GraphMap>,
+        metrics: State<Metrics>,
+        graph_name: String,
+        link: JSON<LinkCreation>)
+        -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("link", || {
+        state::commit(&graphs, link_create(&graphs, graph_name, link.into_inner()))
+    })
+}
 
-
-#[get("/graph/<graph_name>/brick/<brick_name>/delete")]
-fn brick_delete(graphs: State<GraphMap>,
-                graph_name: String,
-                brick_name: String)
-                -> Option<JSON<Value>> {
-    let mut map = graphs.write().unwrap();
-    let g = match map.get_mut(&graph_name) {
-        Some(g) => g,
-        None => return None,
-    };
-
-    let mut g = g.write().unwrap();
-    match g.graph.bricks.remove(&brick_name) {
-        None => None,
-        Some(_) => Some(result(true, "")),
+// Take west and east bricks out of the graph so both can be borrowed mutably.
+fn bricks_take(g: &mut RpgGraph, west: &str, east: &str) -> ApiResult<(Brick, Brick)> {
+    if west == east {
+        return Err(ApiError::InvalidArgument(String::from("cannot link a brick with itself")));
+    }
+    let w = g.graph.bricks.remove(west);
+    let e = g.graph.bricks.remove(east);
+    match (w, e) {
+        (Some(w), Some(e)) => Ok((w, e)),
+        (None, None) => Err(ApiError::NotFound(String::from("west and east bricks not found"))),
+        (None, Some(e)) => {
+            g.graph.bricks.insert(String::from(east), e);
+            Err(ApiError::NotFound(String::from("west brick not found")))
+        }
+        (Some(w), None) => {
+            g.graph.bricks.insert(String::from(west), w);
+            Err(ApiError::NotFound(String::from("east brick not found")))
+        }
     }
 }
 
-#[derive(FromForm)]
-struct NopCreation {
-    name: String,
+fn link_create(graphs: &GraphMap, graph_name: String, link: LinkCreation) -> ApiResult<JSON<Value>> {
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        capture::link_check(g, &link.west, &link.east)?;
+        link_add(g, link)?;
+        Ok(success())
+    })
 }
 
-#[get("/graph/<graph_name>/brick/new/nop?<nop>")]
-fn nop_new(graphs: State<GraphMap>, graph_name: String, nop: NopCreation) -> Option<JSON<Value>> {
-    let mut map = graphs.write().unwrap();
-    let g = match map.get_mut(&graph_name) {
-        Some(g) => g,
-        None => return None,
-    };
-
-    let mut g = g.write().unwrap();
-    if g.graph.bricks.get(&nop.name).is_some() {
-        return Some(result(false, "brick already exists"));
+// Packetgraph bricks of two bricks to link or unlink.
+fn bricks_ptr(g: &RpgGraph, west: &str, east: &str) -> ApiResult<(*mut PgBrick, *mut PgBrick)> {
+    if west == east {
+        return Err(ApiError::InvalidArgument(String::from("cannot link a brick with itself")));
+    }
+    match (g.brick_ptr(west), g.brick_ptr(east)) {
+        (Some(w), Some(e)) => Ok((w, e)),
+        (None, None) => Err(ApiError::NotFound(String::from("west and east bricks not found"))),
+        (None, Some(_)) => Err(ApiError::NotFound(String::from("west brick not found"))),
+        (Some(_), None) => Err(ApiError::NotFound(String::from("east brick not found"))),
     }
-
-    g.graph
-        .bricks
-        .insert(nop.name.clone(), Brick::Nop(Nop::new(nop.name)));
-    Some(result(true, ""))
 }
 
-#[derive(FromForm)]
-struct TapCreation {
-    name: String,
+// Bricks which are not pg bricks are linked by packetgraph directly.
+fn bricks_link(g: &mut RpgGraph, west: &str, east: &str) -> ApiResult<()> {
+    let (wp, ep) = bricks_ptr(g, west, east)?;
+    if g.sys.contains_key(west) || g.sys.contains_key(east) {
+        return sys::link(wp, ep).map_err(ApiError::PgError);
+    }
+    let (mut w, mut e) = bricks_take(g, west, east)?;
+    let ret = w.link(&mut e);
+    g.graph.bricks.insert(String::from(west), w);
+    g.graph.bricks.insert(String::from(east), e);
+    ret.map_err(ApiError::pg)
 }
 
-#[get("/graph/<graph_name>/brick/new/tap?<tap>")]
-fn tap_new(graphs: State<GraphMap>, graph_name: String, tap: TapCreation) -> Option<JSON<Value>> {
-    let mut map = graphs.write().unwrap();
-    let g = match map.get_mut(&graph_name) {
-        Some(g) => g,
-        None => return None,
-    };
-
-    let mut g = g.write().unwrap();
-    if g.graph.bricks.get(&tap.name).is_some() {
-        return Some(result(false, "brick already exists"));
+fn bricks_unlink(g: &mut RpgGraph, west: &str, east: &str) -> ApiResult<()> {
+    let (wp, ep) = bricks_ptr(g, west, east)?;
+    if g.sys.contains_key(west) || g.sys.contains_key(east) {
+        return sys::unlink_edge(wp, ep).map_err(ApiError::PgError);
     }
+    let (mut w, mut e) = bricks_take(g, west, east)?;
+    let ret = w.unlink_from(&mut e);
+    g.graph.bricks.insert(String::from(west), w);
+    g.graph.bricks.insert(String::from(east), e);
+    ret.map_err(ApiError::pg)
+}
 
-    g.graph
-        .bricks
-        .insert(tap.name.clone(), Brick::Tap(Tap::new(tap.name)));
-    Some(result(true, ""))
+fn link_add(g: &mut RpgGraph, mut link: LinkCreation) -> ApiResult<()> {
+    bricks_ptr(g, &link.west, &link.east)?;
+    let west_port = port_pick(g, &link.west, true, link.west_port)?;
+    let east_port = port_pick(g, &link.east, false, link.east_port)?;
+    bricks_link(g, &link.west, &link.east)?;
+    link.west_port = Some(west_port);
+    link.east_port = Some(east_port);
+    g.links.push(link);
+    Ok(())
 }
 
 #[derive(FromForm)]
-struct HubCreation {
-    name: String,
-    west_ports: u32,
-    east_ports: u32,
+struct LinkDeletion {
+    west: String,
+    east: String,
 }
 
-#[get("/graph/<graph_name>/brick/new/hub?<hub>")]
-fn hub_new(graphs: State<GraphMap>, graph_name: String, hub: HubCreation) -> Option<JSON<Value>> {
-    let mut map = graphs.write().unwrap();
-    let g = match map.get_mut(&graph_name) {
-        Some(g) => g,
-        None => return None,
-    };
-
-    let mut g = g.write().unwrap();
-    if g.graph.bricks.get(&hub.name).is_some() {
-        return Some(result(false, "brick already exists"));
-    }
-
-    g.graph
-        .bricks
-        .insert(hub.name.clone(),
-                Brick::Hub(Hub::new(hub.name, hub.west_ports, hub.east_ports)));
-    Some(result(true, ""))
+#[delete("/graph/<graph_name>/link/<west>/<east>")]
+fn unlink_from(graphs: State<GraphMap>,
+               metrics: State<Metrics>,
+               graph_name: String,
+               west: String,
+               east: String)
+               -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("unlink_from", || {
+        state::commit(&graphs,
+                      link_remove(&graphs, graph_name, LinkDeletion { west: west, east: east }))
+    })
 }
 
-#[derive(FromForm)]
-struct SwitchCreation {
-    name: String,
-    west_ports: u32,
-    east_ports: u32,
-    side: String,
+fn link_remove(graphs: &GraphMap, graph_name: String, unlink: LinkDeletion) -> ApiResult<JSON<Value>> {
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        vtep::link_check(g, &unlink.west, &unlink.east)?;
+        capture::link_check(g, &unlink.west, &unlink.east)?;
+        bricks_unlink(g, &unlink.west, &unlink.east)?;
+        let pos = g.links.iter().position(|l| l.west == unlink.west && l.east == unlink.east);
+        if let Some(pos) = pos {
+            g.links.remove(pos);
+        }
+        Ok(success())
+    })
 }
 
-#[get("/graph/<graph_name>/brick/new/switch?<switch>")]
-fn switch_new(graphs: State<GraphMap>,
-              graph_name: String,
-              switch: SwitchCreation)
-              -> Option<JSON<Value>> {
-    let side = match Side::from_str(switch.side.as_str()) {
-        Ok(s) => s,
-        Err(_) => return Some(result(false, "choose west or east for side parameter")),
-    };
-    let mut map = graphs.write().unwrap();
-    let g = match map.get_mut(&graph_name) {
-        Some(g) => g,
-        None => return None,
-    };
+#[delete("/graph/<graph_name>/brick/<brick_name>/link")]
+fn unlink(graphs: State<GraphMap>,
+          metrics: State<Metrics>,
+          graph_name: String,
+          brick_name: String)
+          -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("unlink", || {
+        state::commit(&graphs, brick_unlink(&graphs, graph_name, brick_name))
+    })
+}
 
-    let mut g = g.write().unwrap();
-    if g.graph.bricks.get(&switch.name).is_some() {
-        return Some(result(false, "brick already exists"));
-    }
+fn brick_unlink(graphs: &GraphMap, graph_name: String, brick_name: String) -> ApiResult<JSON<Value>> {
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        for l in g.links.iter().filter(|l| l.west == brick_name || l.east == brick_name) {
+            vtep::link_check(g, &l.west, &l.east)?;
+            capture::link_check(g, &l.west, &l.east)?;
+        }
+        match g.sys.get(&brick_name).map(|b| b.ptr()) {
+            Some(b) => sys::unlink(b).map_err(ApiError::PgError)?,
+            None => {
+                brick_find(g, &brick_name)?.unlink();
+            }
+        }
+        g.links.retain(|l| l.west != brick_name && l.east != brick_name);
+        Ok(success())
+    })
+}
 
-    g.graph
-        .bricks
-        .insert(switch.name.clone(),
-                Brick::Switch(Switch::new(switch.name,
-                                          switch.west_ports,
-                                          switch.east_ports,
-                                          side)));
-    Some(result(true, ""))
+#[delete("/graph/<graph_name>/brick/<brick_name>")]
+fn brick_delete(graphs: State<GraphMap>,
+                metrics: State<Metrics>,
+                graph_name: String,
+                brick_name: String)
+                -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("brick_delete", || {
+        state::commit(&graphs, brick_remove(&graphs, graph_name, brick_name))
+    })
 }
 
-#[derive(FromForm)]
-struct NicCreation {
-    name: String,
-    vdev: Option<String>,
-    port: Option<u8>,
+fn brick_remove(graphs: &GraphMap, graph_name: String, brick_name: String) -> ApiResult<JSON<Value>> {
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        vtep::unbound_check(g, &brick_name)?;
+        queue::unfriended_check(g, &brick_name)?;
+        capture::brick_check(g, &brick_name)?;
+        match g.brick_destroy(&brick_name) {
+            false => Err(ApiError::NotFound(format!("brick {} not found", brick_name))),
+            true => {
+                g.specs.remove(&brick_name);
+                g.firewall_rules.remove(&brick_name);
+                g.vteps.remove(&brick_name);
+                g.links.retain(|l| l.west != brick_name && l.east != brick_name);
+                Ok(success())
+            }
+        }
+    })
 }
 
-#[get("/graph/<graph_name>/brick/new/nic?<nic>")]
-fn nic_new(graphs: State<GraphMap>, graph_name: String, nic: NicCreation) -> Option<JSON<Value>> {
-    let mut map = graphs.write().unwrap();
-    let g = match map.get_mut(&graph_name) {
-        Some(g) => g,
-        None => return None,
-    };
+// Remove brick `name` with its parameters and links.
+fn brick_detach(g: &mut RpgGraph, name: &str) {
+    g.brick_destroy(name);
+    g.specs.remove(name);
+    g.links.retain(|l| l.west != name && l.east != name);
+}
 
-    if nic.vdev.is_none() && nic.port.is_none() {
-        return Some(result(false, "must specify either 'port' or 'vdev' parameters"));
+// Build a brick from `spec` and give it the links of brick `name`, which must
+// have been detached.
+fn brick_attach(g: &mut RpgGraph, name: &str, links: &[LinkCreation], spec: BrickCreation) -> ApiResult<()> {
+    let new_name = String::from(spec.name());
+    let brick = spec.build()?;
+    g.brick_insert(new_name.clone(), brick);
+    g.specs.insert(new_name.clone(), spec);
+    for l in links {
+        let mut link = l.clone();
+        if link.west == name {
+            link.west = new_name.clone();
+        }
+        if link.east == name {
+            link.east = new_name.clone();
+        }
+        link.west_port = None;
+        link.east_port = None;
+        link_add(g, link)?;
     }
+    Ok(())
+}
 
-    let mut g = g.write().unwrap();
-    if g.graph.bricks.get(&nic.name).is_some() {
-        return Some(result(false, "brick already exists"));
+// Replace brick `name` by a new brick built from `spec` and link the new brick
+// to the neighbours of the old one. Links keep their order on each side but
+// may be given lower ports if some were free.
+//
+// The old brick is destroyed before the new one is built as both may need the
+// same resource (like the port of a nic), traffic through the brick stops
+// meanwhile. Parameters are checked first and the old brick is built again if
+// the new one cannot be built or linked.
+fn brick_rebuild(g: &mut RpgGraph, name: &str, spec: BrickCreation) -> ApiResult<()> {
+    let new_name = String::from(spec.name());
+    let old_spec = match g.specs.get(name) {
+        Some(s) => s.clone(),
+        None => return Err(ApiError::NotFound(format!("brick {} not found", name))),
+    };
+    if new_name != name {
+        brick_check_free(g, &new_name)?;
     }
-
-    let nic_brick = match nic.vdev.is_some() {
-        true => Nic::new(nic.name.clone(), nic.vdev.unwrap()),
-        false => Nic::new_port(nic.name.clone(), nic.port.unwrap()),
+    vtep::unbound_check(g, name)?;
+    queue::unfriended_check(g, name)?;
+    capture::brick_check(g, name)?;
+    spec.check()?;
+    let mut links: Vec<LinkCreation> = g.links
+        .iter()
+        .filter(|l| l.west == name || l.east == name)
+        .cloned()
+        .collect();
+    links.sort_by_key(|l| if l.west == name { (1, l.west_port) } else { (0, l.east_port) });
+    let west_links = links.iter().filter(|l| l.east == name).count() as u32;
+    let east_links = links.len() as u32 - west_links;
+    let fits = match spec.ports() {
+        (Some(w), Some(e)) => west_links <= w && east_links <= e,
+        _ => west_links + east_links <= 1,
     };
-    let nic_brick = match nic_brick {
-        Ok(n) => n,
-        Err(e) => return Some(result(false, format!("cannot create nic: {}", e))),
+    if !fits {
+        return Err(ApiError::InvalidArgument(format!("brick {} cannot take the {} west and {} east links of brick {}",
+                                                     new_name, west_links, east_links, name)));
+    }
+    brick_detach(g, name);
+    let err = match brick_attach(g, name, &links, spec) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
     };
-    g.graph.bricks.insert(nic.name, Brick::Nic(nic_brick));
-    Some(result(true, ""))
+    brick_detach(g, &new_name);
+    let mut ret = brick_attach(g, name, &links, old_spec);
+    if ret.is_ok() {
+        ret = firewall_restore(g, name);
+    }
+    if ret.is_ok() {
+        ret = vtep::restore(g, name);
+    }
+    match ret {
+        Ok(()) => Err(err),
+        Err(e) => {
+            brick_detach(g, name);
+            g.firewall_rules.remove(name);
+            g.vteps.remove(name);
+            Err(ApiError::PgError(format!("{}, brick {} cannot be built again and is removed: {}",
+                                          err.description(),
+                                          name,
+                                          e.description())))
+        }
+    }
 }
 
-#[derive(FromForm)]
-struct FirewallCreation {
-    name: String,
+#[put("/graph/<graph_name>/brick/<brick_name>", format = "application/json", data = "<brick>")]
+fn brick_put(graphs: State<GraphMap>,
+             metrics: State<Metrics>,
+             graph_name: String,
+             brick_name: String,
+             brick: JSON<BrickCreation>)
+             -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("brick_put", || {
+        state::commit(&graphs, brick_replace(&graphs, graph_name, brick_name, brick.into_inner()))
+    })
+}
+
+// Replace a brick by a new one, which may have another name, linked to the
+// same bricks.
+fn brick_replace(graphs: &GraphMap,
+                 graph_name: String,
+                 brick_name: String,
+                 spec: BrickCreation)
+                 -> ApiResult<JSON<Value>> {
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        brick_rebuild(g, &brick_name, spec)?;
+        // The new brick starts without firewall rules nor VNIs.
+        g.firewall_rules.remove(&brick_name);
+        g.vteps.remove(&brick_name);
+        Ok(success())
+    })
 }
 
-#[get("/graph/<graph_name>/brick/new/firewall?<firewall>")]
-fn firewall_new(graphs: State<GraphMap>,
+#[post("/graph/<graph_name>/brick/<brick_name>/rename", format = "application/json", data = "<rename>")]
+fn brick_rename(graphs: State<GraphMap>,
+                metrics: State<Metrics>,
                 graph_name: String,
-                firewall: FirewallCreation)
-                -> Option<JSON<Value>> {
-    let mut map = graphs.write().unwrap();
-    let g = match map.get_mut(&graph_name) {
-        Some(g) => g,
-        None => return None,
-    };
+                brick_name: String,
+                rename: JSON<Rename>)
+                -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("brick_rename", || {
+        let ret = brick_name_change(&graphs, graph_name, brick_name, rename.into_inner().name);
+        state::commit(&graphs, ret)
+    })
+}
 
-    let mut g = g.write().unwrap();
-    if g.graph.bricks.get(&firewall.name).is_some() {
-        return Some(result(false, "brick already exists"));
+// Give firewall rules and VNIs of brick `from` to brick `to`.
+fn brick_rules_move(g: &mut RpgGraph, from: &str, to: &str) {
+    if let Some(rules) = g.firewall_rules.remove(from) {
+        g.firewall_rules.insert(String::from(to), rules);
+    }
+    if let Some(vnis) = g.vteps.remove(from) {
+        g.vteps.insert(String::from(to), vnis);
     }
-
-    g.graph
-        .bricks
-        .insert(firewall.name.clone(),
-                Brick::Firewall(Firewall::new(firewall.name)));
-    Some(result(true, ""))
+}
+
+// pg cannot rename a brick, it is replaced by a new brick with the new name
+// which takes its links, firewall rules and VNIs. Traffic through the brick
+// stops meanwhile. The brick gets its old name back if its rules or VNIs
+// cannot be loaded again.
+fn brick_name_change(graphs: &GraphMap,
+                     graph_name: String,
+                     brick_name: String,
+                     new_name: String)
+                     -> ApiResult<JSON<Value>> {
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        let old_spec = match g.specs.get(&brick_name) {
+            Some(s) => s.clone(),
+            None => return Err(ApiError::NotFound(format!("brick {} not found", brick_name))),
+        };
+        if new_name == brick_name {
+            return Ok(success());
+        }
+        let mut spec = old_spec.clone();
+        spec.rename(new_name.clone());
+        brick_rebuild(g, &brick_name, spec)?;
+        brick_rules_move(g, &brick_name, &new_name);
+        let err = match firewall_restore(g, &new_name).and_then(|_| vtep::restore(g, &new_name)) {
+            Ok(()) => return Ok(success()),
+            Err(e) => e,
+        };
+        let ret = brick_rebuild(g, &new_name, old_spec).and_then(|_| {
+            brick_rules_move(g, &new_name, &brick_name);
+            firewall_restore(g, &brick_name)?;
+            vtep::restore(g, &brick_name)
+        });
+        match ret {
+            Ok(()) => Err(err),
+            Err(e) => {
+                Err(ApiError::PgError(format!("{}, brick {} cannot get its name back: {}",
+                                              err.description(),
+                                              new_name,
+                                              e.description())))
+            }
+        }
+    })
+}
+
+#[derive(Deserialize)]
+struct BrickResize {
+    west_ports: Option<u32>,
+    east_ports: Option<u32>,
 }
 
 #[derive(FromForm)]
+struct BrickResizeParams {
+    // Accept that a linked switch forgets learned MAC addresses.
+    flush: Option<bool>,
+}
+
+// Requests with a query string are tried first by the route below.
+#[put("/graph/<graph_name>/brick/<brick_name>/ports",
+      format = "application/json",
+      data = "<ports>",
+      rank = 2)]
+fn brick_ports_put(graphs: State<GraphMap>,
+                   metrics: State<Metrics>,
+                   graph_name: String,
+                   brick_name: String,
+                   ports: JSON<BrickResize>)
+                   -> Tracked<ApiResult<JSON<BrickDescription>>> {
+    metrics.track("brick_ports_put", || {
+        state::commit(&graphs, brick_resize(&graphs, graph_name, brick_name, ports.into_inner(), false))
+    })
+}
+
+#[put("/graph/<graph_name>/brick/<brick_name>/ports?<params>",
+      format = "application/json",
+      data = "<ports>",
+      rank = 1)]
+fn brick_ports_put_flush(graphs: State<GraphMap>,
+                         metrics: State<Metrics>,
+                         graph_name: String,
+                         brick_name: String,
+                         ports: JSON<BrickResize>,
+                         params: BrickResizeParams)
+                         -> Tracked<ApiResult<JSON<BrickDescription>>> {
+    metrics.track("brick_ports_put", || {
+        let flush = params.flush.unwrap_or(false);
+        state::commit(&graphs, brick_resize(&graphs, graph_name, brick_name, ports.into_inner(), flush))
+    })
+}
+
+// Change port counts of a hub or a switch. The brick is built again so
+// switches forget learned MAC addresses, which is refused for linked switches
+// unless `flush` is set, and links may move to lower ports: the new ports are
+// given back.
+fn brick_resize(graphs: &GraphMap,
+                graph_name: String,
+                brick_name: String,
+                ports: BrickResize,
+                flush: bool)
+                -> ApiResult<JSON<BrickDescription>> {
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        let mut spec = match g.specs.get(&brick_name) {
+            Some(s) => s.clone(),
+            None => return Err(ApiError::NotFound(format!("brick {} not found", brick_name))),
+        };
+        let linked = g.links.iter().any(|l| l.west == brick_name || l.east == brick_name);
+        if let BrickCreation::Switch(_) = spec {
+            if linked && !flush {
+                return Err(ApiError::Conflict(format!("switch {} has links and would forget learned MAC addresses, \
+                                                       resize it with flush=true",
+                                                      brick_name)));
+            }
+        }
+        let (west_ports, east_ports) = {
+            let (w, e) = match spec {
+                BrickCreation::Hub(ref mut b) => (&mut b.west_ports, &mut b.east_ports),
+                BrickCreation::Switch(ref mut b) => (&mut b.west_ports, &mut b.east_ports),
+                _ => return Err(ApiError::InvalidArgument(String::from("only hub and switch bricks can be resized"))),
+            };
+            *w = ports.west_ports.unwrap_or(*w);
+            *e = ports.east_ports.unwrap_or(*e);
+            (*w, *e)
+        };
+        for &(east_side, count) in [(false, west_ports), (true, east_ports)].iter() {
+            if let Some(p) = ports_used(g, &brick_name, east_side).into_iter().find(|&p| p >= count) {
+                let side = if east_side { "east" } else { "west" };
+                return Err(ApiError::Conflict(format!("port {} on {} side of brick {} is used", p, side, brick_name)));
+            }
+        }
+        brick_rebuild(g, &brick_name, spec)?;
+        BrickDescription::new(g, &brick_name).map(JSON)
+    })
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+enum BrickCreation {
+    #[serde(rename = "nop")]
+    Nop(NopCreation),
+    #[serde(rename = "tap")]
+    Tap(TapCreation),
+    #[serde(rename = "hub")]
+    Hub(HubCreation),
+    #[serde(rename = "switch")]
+    Switch(SwitchCreation),
+    #[serde(rename = "nic")]
+    Nic(NicCreation),
+    #[serde(rename = "firewall")]
+    Firewall(FirewallCreation),
+    #[serde(rename = "vhost")]
+    Vhost(VhostCreation),
+    #[serde(rename = "vtep")]
+    Vtep(VtepCreation),
+    #[serde(rename = "antispoof")]
+    Antispoof(AntispoofCreation),
+    #[serde(rename = "queue")]
+    Queue(QueueCreation),
+}
+
+impl BrickCreation {
+    fn name(&self) -> &str {
+        match *self {
+            BrickCreation::Nop(ref b) => b.name.as_str(),
+            BrickCreation::Tap(ref b) => b.name.as_str(),
+            BrickCreation::Hub(ref b) => b.name.as_str(),
+            BrickCreation::Switch(ref b) => b.name.as_str(),
+            BrickCreation::Nic(ref b) => b.name.as_str(),
+            BrickCreation::Firewall(ref b) => b.name.as_str(),
+            BrickCreation::Vhost(ref b) => b.name.as_str(),
+            BrickCreation::Vtep(ref b) => b.name.as_str(),
+            BrickCreation::Antispoof(ref b) => b.name.as_str(),
+            BrickCreation::Queue(ref b) => b.name.as_str(),
+        }
+    }
+
+    fn rename(&mut self, name: String) {
+        match *self {
+            BrickCreation::Nop(ref mut b) => b.name = name,
+            BrickCreation::Tap(ref mut b) => b.name = name,
+            BrickCreation::Hub(ref mut b) => b.name = name,
+            BrickCreation::Switch(ref mut b) => b.name = name,
+            BrickCreation::Nic(ref mut b) => b.name = name,
+            BrickCreation::Firewall(ref mut b) => b.name = name,
+            BrickCreation::Vhost(ref mut b) => b.name = name,
+            BrickCreation::Vtep(ref mut b) => b.name = name,
+            BrickCreation::Antispoof(ref mut b) => b.name = name,
+            BrickCreation::Queue(ref mut b) => b.name = name,
+        }
+    }
+
+    // Number of ports on west and east sides, None for bricks having a single
+    // port which can be used on any side.
+    fn ports(&self) -> (Option<u32>, Option<u32>) {
+        match *self {
+            BrickCreation::Hub(ref hub) => (Some(hub.west_ports), Some(hub.east_ports)),
+            BrickCreation::Switch(ref switch) => (Some(switch.west_ports), Some(switch.east_ports)),
+            BrickCreation::Nop(_) | BrickCreation::Firewall(_) | BrickCreation::Antispoof(_) => (Some(1), Some(1)),
+            // A single port on the output side.
+            BrickCreation::Vtep(ref vtep) => {
+                match Side::from_str(vtep.side.as_str()) {
+                    Ok(Side::East) => (Some(vtep.ports), Some(1)),
+                    _ => (Some(1), Some(vtep.ports)),
+                }
+            }
+            BrickCreation::Tap(_) |
+            BrickCreation::Nic(_) |
+            BrickCreation::Vhost(_) |
+            BrickCreation::Queue(_) => (None, None),
+        }
+    }
+
+    // Check parameters which don't need packetgraph, so a brick is not
+    // destroyed for a replacement which cannot be built.
+    fn check(&self) -> ApiResult<()> {
+        match *self {
+            BrickCreation::Switch(ref switch) => side_parse(switch.side.as_str()).map(|_| ()),
+            BrickCreation::Nic(ref nic) if nic.vdev.is_none() && nic.port.is_none() => Err(nic_params_missing()),
+            BrickCreation::Vtep(ref vtep) => {
+                side_parse(vtep.side.as_str())?;
+                ipv4_parse(vtep.ip.as_str())?;
+                mac_parse(vtep.mac.as_str()).map(|_| ())
+            }
+            BrickCreation::Antispoof(ref antispoof) => antispoof::check(antispoof),
+            BrickCreation::Vhost(ref vhost) => {
+                let dir = vhost_socket_dir(vhost);
+                match sys::vhost_started_dir() {
+                    Some(ref d) if *d != dir => {
+                        Err(ApiError::Conflict(format!("vhost sockets are already created in {}", d)))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            BrickCreation::Queue(ref queue) if queue.size.unwrap_or(0) > i32::max_value() as u32 => {
+                Err(ApiError::InvalidArgument(format!("queue size cannot exceed {}", i32::max_value())))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn build(&self) -> ApiResult<AnyBrick> {
+        let brick = match *self {
+            BrickCreation::Nop(ref nop) => Brick::Nop(Nop::new(nop.name.clone())),
+            BrickCreation::Tap(ref tap) => Brick::Tap(Tap::new(tap.name.clone())),
+            BrickCreation::Hub(ref hub) => Brick::Hub(Hub::new(hub.name.clone(), hub.west_ports, hub.east_ports)),
+            BrickCreation::Switch(ref switch) => {
+                let side = side_parse(switch.side.as_str())?;
+                Brick::Switch(Switch::new(switch.name.clone(), switch.west_ports, switch.east_ports, side))
+            }
+            BrickCreation::Nic(ref nic) => {
+                let nic_brick = match (&nic.vdev, nic.port) {
+                    (&Some(ref vdev), _) => Nic::new(nic.name.clone(), vdev.clone()),
+                    (&None, Some(port)) => Nic::new_port(nic.name.clone(), port),
+                    (&None, None) => return Err(nic_params_missing()),
+                };
+                match nic_brick {
+                    Ok(n) => Brick::Nic(n),
+                    Err(e) => return Err(ApiError::PgError(format!("cannot create nic: {}", e))),
+                }
+            }
+            BrickCreation::Firewall(ref firewall) => Brick::Firewall(Firewall::new(firewall.name.clone())),
+            BrickCreation::Vhost(ref vhost) => {
+                self.check()?;
+                let dir = vhost_socket_dir(vhost);
+                return match sys::vhost_new(vhost.name.as_str(), dir.as_str()) {
+                    Ok(b) => Ok(AnyBrick::Sys(b)),
+                    Err(e) => Err(ApiError::PgError(format!("cannot create vhost: {}", e))),
+                };
+            }
+            BrickCreation::Vtep(ref vtep) => {
+                let side = side_parse(vtep.side.as_str())?;
+                let ip = ipv4_parse(vtep.ip.as_str())?;
+                let mac = mac_parse(vtep.mac.as_str())?;
+                let udp_port = vtep.udp_port.unwrap_or(VXLAN_PORT);
+                return match sys::vtep_new(vtep.name.as_str(), vtep.ports, side, ip, mac, udp_port) {
+                    Ok(b) => Ok(AnyBrick::Sys(b)),
+                    Err(e) => Err(ApiError::PgError(format!("cannot create vtep: {}", e))),
+                };
+            }
+            BrickCreation::Antispoof(ref antispoof) => return antispoof::build(antispoof).map(AnyBrick::Sys),
+            BrickCreation::Queue(ref queue) => {
+                self.check()?;
+                return match sys::queue_new(queue.name.as_str(), queue.size.unwrap_or(0)) {
+                    Ok(b) => Ok(AnyBrick::Sys(b)),
+                    Err(e) => Err(ApiError::PgError(format!("cannot create queue: {}", e))),
+                };
+            }
+        };
+        Ok(AnyBrick::Pg(brick))
+    }
+}
+
+// Directory of vhost-user sockets when not given at brick creation.
+fn vhost_dir() -> String {
+    let dir = match config::active() {
+        Some(c) => c.get_str("vhost_dir").ok().map(String::from),
+        None => None,
+    };
+    dir.unwrap_or_else(|| String::from("/tmp"))
+}
+
+fn vhost_socket_dir(vhost: &VhostCreation) -> String {
+    match vhost.socket_dir {
+        Some(ref d) => d.clone(),
+        None => vhost_dir(),
+    }
+}
+
+/// IANA VXLAN port.
+const VXLAN_PORT: u16 = 4789;
+
+fn nic_params_missing() -> ApiError {
+    ApiError::InvalidArgument(String::from("must specify either 'port' or 'vdev' parameters"))
+}
+
+#[post("/graph/<graph_name>/brick", format = "application/json", data = "<brick>")]
+fn brick_new(graphs: State<GraphMap>,
+             metrics: State<Metrics>,
+             graph_name: String,
+             brick: JSON<BrickCreation>)
+             -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("brick_new", || {
+        state::commit(&graphs, brick_create(&graphs, graph_name, brick.into_inner()))
+    })
+}
+
+fn brick_create(graphs: &GraphMap, graph_name: String, spec: BrickCreation) -> ApiResult<JSON<Value>> {
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        brick_check_free(g, spec.name())?;
+        let brick = spec.build()?;
+        let name = String::from(spec.name());
+        g.brick_insert(name.clone(), brick);
+        g.specs.insert(name, spec);
+        Ok(success())
+    })
+}
+
+#[derive(FromForm, Serialize, Deserialize, Clone)]
+struct NopCreation {
+    name: String,
+}
+
+#[derive(FromForm, Serialize, Deserialize, Clone)]
+struct TapCreation {
+    name: String,
+}
+
+#[derive(FromForm, Serialize, Deserialize, Clone)]
+struct HubCreation {
+    name: String,
+    west_ports: u32,
+    east_ports: u32,
+}
+
+#[derive(FromForm, Serialize, Deserialize, Clone)]
+struct SwitchCreation {
+    name: String,
+    west_ports: u32,
+    east_ports: u32,
+    side: String,
+}
+
+#[derive(FromForm, Serialize, Deserialize, Clone)]
+struct NicCreation {
+    name: String,
+    vdev: Option<String>,
+    port: Option<u8>,
+}
+
+#[derive(FromForm, Serialize, Deserialize, Clone)]
+struct FirewallCreation {
+    name: String,
+}
+
+#[derive(FromForm, Serialize, Deserialize, Clone)]
+struct VhostCreation {
+    name: String,
+    // Directory of the socket, all vhost-user bricks share the directory of
+    // the first one.
+    #[serde(default)]
+    socket_dir: Option<String>,
+}
+
+#[derive(FromForm, Serialize, Deserialize, Clone)]
+struct VtepCreation {
+    name: String,
+    // Side linked to the physical network, neighbours are linked on the other
+    // side.
+    side: String,
+    // Number of neighbours.
+    ports: u32,
+    ip: String,
+    mac: String,
+    #[serde(default)]
+    udp_port: Option<u16>,
+}
+
+#[derive(FromForm, Serialize, Deserialize, Clone)]
+struct QueueCreation {
+    name: String,
+    // Packets the queue can keep, packetgraph picks it if not set.
+    #[serde(default)]
+    size: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct AntispoofCreation {
+    name: String,
+    // Side linked to the network, packets going out of it must come from
+    // `mac`.
+    side: String,
+    mac: String,
+    // ARP and NDP checks, only listed addresses can be announced.
+    #[serde(default)]
+    arp: bool,
+    #[serde(default)]
+    ndp: bool,
+    #[serde(default)]
+    ipv4: Vec<String>,
+    #[serde(default)]
+    ipv6: Vec<String>,
+}
+
+#[derive(FromForm, Serialize, Deserialize, Clone)]
 struct FirewallRule {
     filter: String,
     side: String,
 }
 
-#[get("/graph/<graph_name>/brick/<brick_name>/firewall/rule?<rule>")]
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+struct FirewallRuleEntry {
+    // Rules saved before ids existed have none, a new id is picked for them.
+    #[serde(default)]
+    id: u32,
+    filter: String,
+    side: String,
+}
+
+impl FirewallRuleEntry {
+    fn new(rule: FirewallRule) -> FirewallRuleEntry {
+        FirewallRuleEntry {
+            id: 0,
+            filter: rule.filter,
+            side: rule.side,
+        }
+    }
+}
+
+#[derive(Default)]
+struct FirewallRules {
+    last_id: u32,
+    rules: Vec<FirewallRuleEntry>,
+    // Rules loaded in the firewall by the last reload.
+    applied: Vec<FirewallRuleEntry>,
+}
+
+#[derive(Serialize)]
+struct FirewallRulesDescription {
+    rules: Vec<FirewallRuleEntry>,
+    applied: bool,
+}
+
+#[get("/graph/<graph_name>/brick/<brick_name>/firewall/rules")]
+fn firewall_rules_get(graphs: State<GraphMap>,
+                      metrics: State<Metrics>,
+                      graph_name: String,
+                      brick_name: String)
+                      -> Tracked<ApiResult<JSON<FirewallRulesDescription>>> {
+    metrics.track("firewall_rules_get", || {
+        graph_find(&graphs, &graph_name)?.exec(move |g| {
+            firewall_find(g, &brick_name)?;
+            let desc = match g.firewall_rules.get(&brick_name) {
+                Some(r) => {
+                    FirewallRulesDescription {
+                        rules: r.rules.clone(),
+                        applied: r.rules == r.applied,
+                    }
+                }
+                None => FirewallRulesDescription { rules: Vec::new(), applied: true },
+            };
+            Ok(JSON(desc))
+        })
+    })
+}
+
+#[post("/graph/<graph_name>/brick/<brick_name>/firewall/rules",
+       format = "application/json",
+       data = "<rule>")]
 fn firewall_rule_add(graphs: State<GraphMap>,
+                     metrics: State<Metrics>,
                      graph_name: String,
                      brick_name: String,
-                     rule: FirewallRule)
-                     -> Option<JSON<Value>> {
-    let mut map = graphs.write().unwrap();
-    let g = match map.get_mut(&graph_name) {
-        Some(g) => g,
-        None => return None,
-    };
-
-    let mut g = g.write().unwrap();
-    let mut b = match g.graph.bricks.get_mut(&brick_name) {
-        Some(b) => b,
-        None => return None,
-    };
+                     rule: JSON<FirewallRule>)
+                     -> Tracked<ApiResult<JSON<FirewallRuleEntry>>> {
+    metrics.track("firewall_rule_add", || {
+        let rule = FirewallRuleEntry::new(rule.into_inner());
+        let ret = firewall_rule_create(&graphs, graph_name, brick_name, rule).map(JSON);
+        state::commit(&graphs, ret)
+    })
+}
 
-    let mut fw = match b.firewall() {
-        Some(fw) => fw,
-        None => return None,
-    };
+// Add a rule to a firewall, a new id is given to the rule if its id is 0.
+fn firewall_rule_create(graphs: &GraphMap,
+                        graph_name: String,
+                        brick_name: String,
+                        mut rule: FirewallRuleEntry)
+                        -> ApiResult<FirewallRuleEntry> {
+    let side = side_parse(rule.side.as_str())?;
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        firewall_find(g, &brick_name)?;
+        let exists = match g.firewall_rules.get(&brick_name) {
+            Some(rules) => rules.rules.iter().any(|r| r.id == rule.id),
+            None => false,
+        };
+        if rule.id != 0 && exists {
+            return Err(ApiError::Conflict(format!("rule {} already exists", rule.id)));
+        }
+        let ret = firewall_find(g, &brick_name)?.rule_add(rule.filter.clone(), side);
+        if let Err(e) = ret {
+            return Err(ApiError::pg(e));
+        }
+        let rules = g.firewall_rules.entry(brick_name).or_insert_with(FirewallRules::default);
+        if rule.id == 0 {
+            rule.id = rules.last_id + 1;
+        }
+        rules.last_id = max(rules.last_id, rule.id);
+        rules.rules.push(rule.clone());
+        Ok(rule)
+    })
+}
 
-    let side = match Side::from_str(rule.side.as_str()) {
-        Ok(s) => s,
-        Err(_) => return Some(result(false, "choose west or east for side parameter")),
-    };
+#[delete("/graph/<graph_name>/brick/<brick_name>/firewall/rule/<id>")]
+fn firewall_rule_delete(graphs: State<GraphMap>,
+                        metrics: State<Metrics>,
+                        graph_name: String,
+                        brick_name: String,
+                        id: u32)
+                        -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("firewall_rule_delete", || {
+        state::commit(&graphs, firewall_rule_remove(&graphs, graph_name, brick_name, id))
+    })
+}
 
-    match fw.rule_add(rule.filter, side) {
-        Ok(_) => Some(result(true, "")),
-        Err(e) => Some(result(false, format!("{}", e))),
-    }
+fn firewall_rule_remove(graphs: &GraphMap,
+                        graph_name: String,
+                        brick_name: String,
+                        id: u32)
+                        -> ApiResult<JSON<Value>> {
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        firewall_find(g, &brick_name)?;
+        let rules = match g.firewall_rules.get_mut(&brick_name) {
+            Some(rules) => {
+                match rules.rules.iter().position(|r| r.id == id) {
+                    Some(pos) => {
+                        rules.rules.remove(pos);
+                        rules.rules.clone()
+                    }
+                    None => return Err(ApiError::NotFound(format!("rule {} not found", id))),
+                }
+            }
+            None => return Err(ApiError::NotFound(format!("rule {} not found", id))),
+        };
+        // pg cannot remove a single rule, other rules are added again.
+        let fw = firewall_find(g, &brick_name)?;
+        fw.flush();
+        for r in rules {
+            let side = side_parse(r.side.as_str())?;
+            if let Err(e) = fw.rule_add(r.filter, side) {
+                return Err(ApiError::pg(e));
+            }
+        }
+        Ok(success())
+    })
 }
 
-#[get("/graph/<graph_name>/brick/<brick_name>/firewall/flush")]
+#[delete("/graph/<graph_name>/brick/<brick_name>/firewall/rules")]
 fn firewall_flush(graphs: State<GraphMap>,
+                  metrics: State<Metrics>,
                   graph_name: String,
                   brick_name: String)
-                  -> Option<JSON<Value>> {
-    let mut map = graphs.write().unwrap();
-    let g = match map.get_mut(&graph_name) {
-        Some(g) => g,
-        None => return None,
-    };
+                  -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("firewall_flush", || {
+        state::commit(&graphs, firewall_rules_flush(&graphs, graph_name, brick_name))
+    })
+}
 
-    let mut g = g.write().unwrap();
-    let mut b = match g.graph.bricks.get_mut(&brick_name) {
-        Some(b) => b,
-        None => return None,
-    };
+fn firewall_rules_flush(graphs: &GraphMap,
+                        graph_name: String,
+                        brick_name: String)
+                        -> ApiResult<JSON<Value>> {
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        firewall_find(g, &brick_name)?.flush();
+        // Keep last id and applied rules so ids are never given twice.
+        if let Some(rules) = g.firewall_rules.get_mut(&brick_name) {
+            rules.rules.clear();
+        }
+        Ok(success())
+    })
+}
 
-    let mut fw = match b.firewall() {
-        Some(fw) => fw,
-        None => return None,
-    };
+// Compile a filter as packetgraph firewalls do.
+fn filter_check(filter: &str) -> Result<BpfFilter, String> {
+    BpfFilter::new(filter)
+}
 
-    fw.flush();
-    Some(result(true, ""))
+#[derive(Deserialize)]
+struct FilterCheck {
+    filter: String,
+    side: Option<String>,
 }
 
-#[get("/graph/<graph_name>/brick/<brick_name>/firewall/reload")]
-fn firewall_reload(graphs: State<GraphMap>,
-                   graph_name: String,
-                   brick_name: String)
-                   -> Option<JSON<Value>> {
-    let mut map = graphs.write().unwrap();
-    let g = match map.get_mut(&graph_name) {
-        Some(g) => g,
-        None => return None,
-    };
+#[derive(FromForm)]
+struct FilterCheckParams {
+    bpf: Option<bool>,
+}
 
-    let mut g = g.write().unwrap();
-    let mut b = match g.graph.bricks.get_mut(&brick_name) {
-        Some(b) => b,
-        None => return None,
-    };
+#[derive(Serialize)]
+struct FilterCheckResult {
+    valid: bool,
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bpf: Option<Vec<BpfInsn>>,
+}
 
-    let mut fw = match b.firewall() {
-        Some(fw) => fw,
-        None => return None,
+fn filter_check_result(check: &FilterCheck, bpf: bool) -> ApiResult<JSON<FilterCheckResult>> {
+    if let Some(ref side) = check.side {
+        side_parse(side.as_str())?;
+    }
+    let res = match filter_check(check.filter.as_str()) {
+        Ok(f) => {
+            FilterCheckResult {
+                valid: true,
+                error: None,
+                bpf: if bpf { Some(f.instructions()) } else { None },
+            }
+        }
+        Err(e) => {
+            FilterCheckResult {
+                valid: false,
+                error: Some(e),
+                bpf: None,
+            }
+        }
     };
+    Ok(JSON(res))
+}
+
+// Requests with a query string are tried first by the route below.
+#[post("/firewall/check", format = "application/json", data = "<check>", rank = 1)]
+fn firewall_filter_check(metrics: State<Metrics>,
+                         check: JSON<FilterCheck>)
+                         -> Tracked<ApiResult<JSON<FilterCheckResult>>> {
+    metrics.track("firewall_filter_check", || filter_check_result(&check, false))
+}
+
+#[post("/firewall/check?<params>", format = "application/json", data = "<check>")]
+fn firewall_filter_check_bpf(metrics: State<Metrics>,
+                             check: JSON<FilterCheck>,
+                             params: FilterCheckParams)
+                             -> Tracked<ApiResult<JSON<FilterCheckResult>>> {
+    metrics.track("firewall_filter_check", || {
+        filter_check_result(&check, params.bpf.unwrap_or(false))
+    })
+}
+
+#[put("/graph/<graph_name>/brick/<brick_name>/firewall/rules",
+      format = "application/json",
+      data = "<rules>")]
+fn firewall_rules_put(graphs: State<GraphMap>,
+                      metrics: State<Metrics>,
+                      graph_name: String,
+                      brick_name: String,
+                      rules: JSON<Vec<FirewallRule>>)
+                      -> Tracked<ApiResult<JSON<FirewallRulesDescription>>> {
+    metrics.track("firewall_rules_put", || {
+        let ret = firewall_rules_replace(&graphs, graph_name, brick_name, rules.into_inner());
+        state::commit(&graphs, ret.map(JSON))
+    })
+}
 
-    match fw.reload() {
-        Ok(_) => Some(result(true, "")),
-        Err(e) => Some(result(false, format!("{}", e))),
+// Add rules to a flushed firewall and reload it.
+fn firewall_rules_load(fw: &mut Firewall, rules: &[FirewallRule]) -> ApiResult<()> {
+    for rule in rules.iter() {
+        let side = side_parse(rule.side.as_str())?;
+        fw.rule_add(rule.filter.clone(), side).map_err(ApiError::pg)?;
     }
+    fw.reload().map_err(ApiError::pg)
 }
 
-fn pooler(graph: Arc<RwLock<RpgGraph>>) {
-    loop {
-        let mut g = graph.write().unwrap();
-        match g.run {
-            true => {
-                g.graph.poll();
-            }
-            false => break,
+// Check every rule then replace all rules of a firewall and reload it, the
+// firewall is left untouched if a rule is invalid and gets its previous rules
+// back if packetgraph refuses the new ones.
+fn firewall_rules_replace(graphs: &GraphMap,
+                          graph_name: String,
+                          brick_name: String,
+                          rules: Vec<FirewallRule>)
+                          -> ApiResult<FirewallRulesDescription> {
+    let g = graph_find(graphs, &graph_name)?;
+    let mut errors = Vec::new();
+    for (index, rule) in rules.iter().enumerate() {
+        let ret = match Side::from_str(rule.side.as_str()) {
+            Ok(_) => filter_check(rule.filter.as_str()).map(|_| ()),
+            Err(_) => Err(String::from("choose west or east for side parameter")),
+        };
+        if let Err(e) = ret {
+            errors.push(RuleError {
+                index: index,
+                filter: rule.filter.clone(),
+                error: e,
+            });
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::InvalidRules(errors));
+    }
+    g.exec(move |g| {
+        let ret = {
+            let fw = firewall_find(g, &brick_name)?;
+            fw.flush();
+            firewall_rules_load(fw, &rules)
+        };
+        if let Err(e) = ret {
+            // Load the previous rules again, pending ones stay pending.
+            firewall_find(g, &brick_name)?.flush();
+            let saved = g.firewall_rules.contains_key(&brick_name);
+            let restored = match saved {
+                true => firewall_restore(g, &brick_name),
+                false => firewall_find(g, &brick_name)?.reload().map_err(ApiError::pg),
+            };
+            return match restored {
+                Ok(()) => Err(e),
+                Err(r) => {
+                    Err(ApiError::PgError(format!("{}, previous rules cannot be loaded again: {}",
+                                                  e.description(),
+                                                  r.description())))
+                }
+            };
+        }
+        let fw_rules = g.firewall_rules.entry(brick_name).or_insert_with(FirewallRules::default);
+        let mut entries = Vec::new();
+        for rule in rules {
+            fw_rules.last_id += 1;
+            entries.push(FirewallRuleEntry {
+                id: fw_rules.last_id,
+                filter: rule.filter,
+                side: rule.side,
+            });
         }
+        fw_rules.rules = entries;
+        fw_rules.applied = fw_rules.rules.clone();
+        Ok(FirewallRulesDescription {
+            rules: fw_rules.rules.clone(),
+            applied: true,
+        })
+    })
+}
+
+// Load rules known for a firewall in a new firewall brick: applied rules are
+// loaded then other rules are added without reload.
+fn firewall_restore(g: &mut RpgGraph, brick_name: &str) -> ApiResult<()> {
+    let (applied, rules) = match g.firewall_rules.get(brick_name) {
+        Some(r) => (r.applied.clone(), r.rules.clone()),
+        None => return Ok(()),
+    };
+    let fw = firewall_find(g, brick_name)?;
+    for r in applied {
+        let side = side_parse(r.side.as_str())?;
+        fw.rule_add(r.filter, side).map_err(ApiError::pg)?;
+    }
+    fw.reload().map_err(ApiError::pg)?;
+    fw.flush();
+    for r in rules {
+        let side = side_parse(r.side.as_str())?;
+        fw.rule_add(r.filter, side).map_err(ApiError::pg)?;
+    }
+    Ok(())
+}
+
+#[post("/graph/<graph_name>/brick/<brick_name>/firewall/reload")]
+fn firewall_reload(graphs: State<GraphMap>,
+                   metrics: State<Metrics>,
+                   graph_name: String,
+                   brick_name: String)
+                   -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("firewall_reload", || {
+        state::commit(&graphs, firewall_apply(&graphs, graph_name, brick_name))
+    })
+}
+
+fn firewall_apply(graphs: &GraphMap,
+                  graph_name: String,
+                  brick_name: String)
+                  -> ApiResult<JSON<Value>> {
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        let ret = firewall_find(g, &brick_name)?.reload();
+        if let Err(e) = ret {
+            return Err(ApiError::pg(e));
+        }
+        if let Some(rules) = g.firewall_rules.get_mut(&brick_name) {
+            rules.applied = rules.rules.clone();
+        }
+        Ok(success())
+    })
+}
+
+fn legacy_routes_enabled() -> bool {
+    match config::active() {
+        Some(c) => c.get_bool("legacy_routes").unwrap_or(false),
+        None => false,
     }
 }
 
 fn rocket_init() -> Rocket {
     pg::init();
-    let graphs = Arc::new(RwLock::new(HashMap::<String, Arc<RwLock<RpgGraph>>>::new()));
-    rocket::ignite()
+    let graphs = Arc::new(RwLock::new(HashMap::<String, Arc<GraphRuntime>>::new()));
+    let rocket = rocket::ignite();
+    // Refuse to start rather than run without the configured cores or graphs.
+    if let Err(e) = cores::init() {
+        panic!("{}", e);
+    }
+    if let Err(e) = state::restore(&graphs) {
+        panic!("{}", e);
+    }
+    let rocket = rocket
         .manage(graphs)
+        .manage(Metrics::new())
+        .catch(error::catchers())
+        .mount("/", metrics::routes())
+        .mount("/", vtep::routes())
+        .mount("/", antispoof::routes())
+        .mount("/", queue::routes())
+        .mount("/", capture::routes())
+        .mount("/", stream::routes())
         .mount("/", routes![index,
                             graphs,
                             graph_new,
                             graph_get,
+                            graph_export,
+                            graph_import,
                             graph_delete,
+                            graph_rename,
+                            graph_start,
+                            graph_stop,
+                            graph_pause,
                             brick_get,
+                            brick_stats,
+                            graph_stats,
+                            brick_new,
+                            brick_delete,
+                            brick_ports_put,
+                            brick_ports_put_flush,
+                            brick_put,
+                            brick_rename,
                             link,
                             unlink,
                             unlink_from,
                             dot_get,
                             dot_get_svg,
-                            brick_delete,
-                            nop_new,
-                            tap_new,
-                            hub_new,
-                            switch_new,
-                            nic_new,
-                            firewall_new,
+                            firewall_rules_get,
                             firewall_rule_add,
+                            firewall_rule_delete,
+                            firewall_rules_put,
+                            firewall_filter_check,
+                            firewall_filter_check_bpf,
                             firewall_flush,
-                            firewall_reload])
+                            firewall_reload]);
+    match legacy_routes_enabled() {
+        true => legacy::mount(rocket),
+        false => rocket,
+    }
 }
 
 fn main() {
@@ -642,9 +1915,14 @@ fn main() {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::collections::HashMap;
+    use std::sync::{Arc, RwLock};
+    use pg;
+    use serde_json;
     use rocket::Rocket;
     use rocket::testing::MockRequest;
-    use rocket::http::{Status, Method};
+    use rocket::http::{Status, Method, ContentType};
+    use rocket_contrib::Value;
 
     fn request_ok(rocket: &Rocket, url: &'static str) {
         let mut req = MockRequest::new(Method::Get, url);
@@ -655,45 +1933,714 @@ mod test {
         //assert_eq!(body_str, Some("Hello, world!".to_string()));
     }
 
+    fn send_status(rocket: &Rocket, method: Method, url: &'static str, body: &'static str, status: Status) {
+        let mut req = MockRequest::new(method, url)
+            .header(ContentType::JSON)
+            .body(body);
+        let response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), status);
+    }
+
+    fn request_json(rocket: &Rocket, url: &'static str) -> Value {
+        let mut req = MockRequest::new(Method::Get, url);
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.body().and_then(|b| b.into_string()).unwrap();
+        serde_json::from_str(body.as_str()).unwrap()
+    }
+
+    fn send_ok(rocket: &Rocket, method: Method, url: &'static str, body: &'static str) {
+        send_status(rocket, method, url, body, Status::Ok);
+    }
+
     #[test]
     fn simple() {
         let r = rocket_init();
-        request_ok(&r, "/graph/new?name=mygraph");
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "mygraph"}"#);
         request_ok(&r, "/graph/mygraph");
-        request_ok(&r, "/graph/mygraph/brick/new/tap?name=tap1");
-        request_ok(&r, "/graph/mygraph/brick/new/nop?name=nop1");
-        request_ok(&r, "/graph/mygraph/brick/new/tap?name=tap2");
-        request_ok(&r, "/graph/mygraph/brick/new/switch?name=switch1&west_ports=2&east_ports=2&side=west");
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "tap", "name": "tap1"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "nop", "name": "nop1"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "tap", "name": "tap2"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick",
+                r#"{"type": "switch", "name": "switch1", "west_ports": 2, "east_ports": 2, "side": "west"}"#);
         request_ok(&r, "/graph/mygraph/brick/tap1");
         request_ok(&r, "/graph/mygraph/brick/nop1");
         request_ok(&r, "/graph/mygraph/brick/tap2");
         request_ok(&r, "/graph/mygraph/brick/switch1");
-        request_ok(&r, "/graph/mygraph/brick/link?west=tap1&east=switch1");
-        request_ok(&r, "/graph/mygraph/brick/link?west=nop1&east=switch1");
-        request_ok(&r, "/graph/mygraph/brick/link?west=switch1&east=tap2");
-        request_ok(&r, "/graph/mygraph/brick/unlink?west=tap1&east=switch1");
+        send_ok(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "tap1", "east": "switch1"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "nop1", "east": "switch1"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "switch1", "east": "tap2"}"#);
+        send_ok(&r, Method::Delete, "/graph/mygraph/link/tap1/switch1", "");
         request_ok(&r, "/graph/mygraph");
-        request_ok(&r, "/graph/mygraph/brick/switch1/unlink");
-        request_ok(&r, "/graph/mygraph/delete");
+        send_ok(&r, Method::Delete, "/graph/mygraph/brick/switch1/link", "");
+        send_ok(&r, Method::Delete, "/graph/mygraph/brick/nop1", "");
+        send_ok(&r, Method::Delete, "/graph/mygraph", "");
     }
 
     #[test]
     fn firewall() {
         let r = rocket_init();
-        request_ok(&r, "/graph/new?name=mygraph");
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "mygraph"}"#);
         request_ok(&r, "/graph/mygraph");
-        request_ok(&r, "/graph/mygraph/brick/new/tap?name=tap1");
-        request_ok(&r, "/graph/mygraph/brick/new/firewall?name=fw");
-        request_ok(&r, "/graph/mygraph/brick/new/tap?name=tap2");
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "tap", "name": "tap1"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "firewall", "name": "fw"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "tap", "name": "tap2"}"#);
         request_ok(&r, "/graph/mygraph/brick/tap1");
         request_ok(&r, "/graph/mygraph/brick/fw");
         request_ok(&r, "/graph/mygraph/brick/tap2");
+        send_ok(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "tap1", "east": "fw"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "fw", "east": "tap2"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick/fw/firewall/rules",
+                r#"{"side": "west", "filter": "src host 10::1"}"#);
+        send_ok(&r, Method::Delete, "/graph/mygraph/brick/fw/firewall/rules", "");
+        send_ok(&r, Method::Post, "/graph/mygraph/brick/fw/firewall/rules",
+                r#"{"side": "west", "filter": "src host 10::1"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick/fw/firewall/rules",
+                r#"{"side": "west", "filter": "src host 10::2"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick/fw/firewall/reload", "");
+    }
+
+    #[test]
+    fn firewall_rule_ids() {
+        let r = rocket_init();
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "rulesgraph"}"#);
+        send_ok(&r, Method::Post, "/graph/rulesgraph/brick", r#"{"type": "tap", "name": "tap1"}"#);
+        send_ok(&r, Method::Post, "/graph/rulesgraph/brick", r#"{"type": "firewall", "name": "fw"}"#);
+        send_ok(&r, Method::Post, "/graph/rulesgraph/brick", r#"{"type": "tap", "name": "tap2"}"#);
+        send_ok(&r, Method::Post, "/graph/rulesgraph/link", r#"{"west": "tap1", "east": "fw"}"#);
+        send_ok(&r, Method::Post, "/graph/rulesgraph/link", r#"{"west": "fw", "east": "tap2"}"#);
+        send_ok(&r, Method::Post, "/graph/rulesgraph/brick/fw/firewall/rules",
+                r#"{"side": "west", "filter": "src host 10::1"}"#);
+        send_ok(&r, Method::Post, "/graph/rulesgraph/brick/fw/firewall/rules",
+                r#"{"side": "west", "filter": "src host 10::2"}"#);
+        let rules = request_json(&r, "/graph/rulesgraph/brick/fw/firewall/rules");
+        assert_eq!(rules["applied"], json!(false));
+        assert_eq!(rules["rules"],
+                   json!([{"id": 1, "side": "west", "filter": "src host 10::1"},
+                          {"id": 2, "side": "west", "filter": "src host 10::2"}]));
+        send_ok(&r, Method::Post, "/graph/rulesgraph/brick/fw/firewall/reload", "");
+        let rules = request_json(&r, "/graph/rulesgraph/brick/fw/firewall/rules");
+        assert_eq!(rules["applied"], json!(true));
+        send_ok(&r, Method::Delete, "/graph/rulesgraph/brick/fw/firewall/rule/1", "");
+        send_status(&r, Method::Delete, "/graph/rulesgraph/brick/fw/firewall/rule/1", "", Status::NotFound);
+        let rules = request_json(&r, "/graph/rulesgraph/brick/fw/firewall/rules");
+        assert_eq!(rules["applied"], json!(false));
+        assert_eq!(rules["rules"], json!([{"id": 2, "side": "west", "filter": "src host 10::2"}]));
+        send_status(&r, Method::Get, "/graph/rulesgraph/brick/tap1/firewall/rules", "", Status::BadRequest);
+        send_ok(&r, Method::Delete, "/graph/rulesgraph", "");
+    }
+
+    #[test]
+    fn firewall_rules_replace() {
+        let r = rocket_init();
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "replacegraph"}"#);
+        send_ok(&r, Method::Post, "/graph/replacegraph/brick", r#"{"type": "tap", "name": "tap1"}"#);
+        send_ok(&r, Method::Post, "/graph/replacegraph/brick", r#"{"type": "firewall", "name": "fw"}"#);
+        send_ok(&r, Method::Post, "/graph/replacegraph/brick", r#"{"type": "tap", "name": "tap2"}"#);
+        send_ok(&r, Method::Post, "/graph/replacegraph/link", r#"{"west": "tap1", "east": "fw"}"#);
+        send_ok(&r, Method::Post, "/graph/replacegraph/link", r#"{"west": "fw", "east": "tap2"}"#);
+        send_ok(&r, Method::Put, "/graph/replacegraph/brick/fw/firewall/rules",
+                r#"[{"side": "west", "filter": "src host 10::3"}, {"side": "east", "filter": "dst host 10::3"}]"#);
+        let rules = request_json(&r, "/graph/replacegraph/brick/fw/firewall/rules");
+        assert_eq!(rules["applied"], json!(true));
+        assert_eq!(rules["rules"],
+                   json!([{"id": 1, "side": "west", "filter": "src host 10::3"},
+                          {"id": 2, "side": "east", "filter": "dst host 10::3"}]));
+        // One bad rule rejects the whole rule set.
+        send_status(&r, Method::Put, "/graph/replacegraph/brick/fw/firewall/rules",
+                    r#"[{"side": "west", "filter": "src host 10::4"}, {"side": "west", "filter": "not a filter"}]"#,
+                    Status::BadRequest);
+        let rules = request_json(&r, "/graph/replacegraph/brick/fw/firewall/rules");
+        assert_eq!(rules["rules"].as_array().map(|r| r.len()), Some(2));
+        send_ok(&r, Method::Delete, "/graph/replacegraph", "");
+    }
+
+    #[test]
+    fn firewall_drops() {
+        let r = rocket_init();
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "dropgraph"}"#);
+        send_ok(&r, Method::Post, "/graph/dropgraph/brick", r#"{"type": "tap", "name": "tap1"}"#);
+        send_ok(&r, Method::Post, "/graph/dropgraph/brick", r#"{"type": "firewall", "name": "fw"}"#);
+        send_ok(&r, Method::Post, "/graph/dropgraph/brick", r#"{"type": "tap", "name": "tap2"}"#);
+        send_ok(&r, Method::Post, "/graph/dropgraph/link", r#"{"west": "tap1", "east": "fw"}"#);
+        send_ok(&r, Method::Post, "/graph/dropgraph/link", r#"{"west": "fw", "east": "tap2"}"#);
+        let metrics = request_string(&r, "/metrics");
+        assert!(metrics.contains(r#"rpg_firewall_dropped_packets_total{graph="dropgraph",brick="fw",side="west"} 0"#));
+        assert!(metrics.contains(r#"rpg_firewall_dropped_packets_total{graph="dropgraph",brick="fw",side="east"} 0"#));
+        let stats = request_json(&r, "/graph/dropgraph/brick/fw/stats");
+        assert_eq!(stats["west_dropped"], json!(0));
+        assert_eq!(stats["east_dropped"], json!(0));
+        send_ok(&r, Method::Delete, "/graph/dropgraph", "");
+    }
+
+    fn request_string(rocket: &Rocket, url: &'static str) -> String {
+        let mut req = MockRequest::new(Method::Get, url);
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::Ok);
+        response.body().and_then(|b| b.into_string()).unwrap()
+    }
+
+    #[test]
+    fn rename() {
+        let r = rocket_init();
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "mygraph"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "tap", "name": "tap1"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "firewall", "name": "fw"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick",
+                r#"{"type": "switch", "name": "switch1", "west_ports": 1, "east_ports": 2, "side": "west"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "tap", "name": "tap2"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "tap1", "east": "fw"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "fw", "east": "switch1"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "switch1", "east": "tap2"}"#);
+        send_ok(&r, Method::Put, "/graph/mygraph/brick/fw/firewall/rules",
+                r#"[{"side": "west", "filter": "src host 10::3"}, {"side": "east", "filter": "dst host 10::3"}]"#);
+
+        send_ok(&r, Method::Post, "/graph/mygraph/brick/fw/rename", r#"{"name": "fw2"}"#);
+        send_status(&r, Method::Get, "/graph/mygraph/brick/fw", "", Status::NotFound);
+        let fw = request_json(&r, "/graph/mygraph/brick/fw2");
+        assert_eq!(fw["west"], json!(["tap1"]));
+        assert_eq!(fw["east"], json!(["switch1"]));
+        let rules = request_json(&r, "/graph/mygraph/brick/fw2/firewall/rules");
+        assert_eq!(rules["applied"], json!(true));
+        assert_eq!(rules["rules"].as_array().map(|r| r.len()), Some(2));
+        send_status(&r, Method::Post, "/graph/mygraph/brick/fw2/rename", r#"{"name": "tap1"}"#,
+                    Status::Conflict);
+
+        send_ok(&r, Method::Post, "/graph/mygraph/brick/switch1/rename", r#"{"name": "switch2"}"#);
+        send_status(&r, Method::Get, "/graph/mygraph/brick/switch1", "", Status::NotFound);
+        let switch = request_json(&r, "/graph/mygraph/brick/switch2");
+        assert_eq!(switch["type_name"], json!("switch"));
+        assert_eq!(switch["west"], json!(["fw2"]));
+        assert_eq!(switch["east"], json!(["tap2"]));
+        let dot = request_string(&r, "/graph/mygraph/dot");
+        assert!(dot.contains("fw2") && dot.contains("switch2"));
+        assert!(!dot.contains("switch1"));
+
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "other"}"#);
+        send_status(&r, Method::Post, "/graph/mygraph/rename", r#"{"name": "other"}"#, Status::Conflict);
+        send_ok(&r, Method::Delete, "/graph/other", "");
+        send_ok(&r, Method::Post, "/graph/mygraph/rename", r#"{"name": "other"}"#);
+        send_status(&r, Method::Get, "/graph/mygraph", "", Status::NotFound);
+        send_status(&r, Method::Get, "/graph/mygraph/dot", "", Status::NotFound);
+        assert_eq!(request_json(&r, "/graph/other")["name"], json!("other"));
+        let dot = request_string(&r, "/graph/other/dot");
+        assert!(dot.contains("switch2"));
+        assert!(!dot.contains("mygraph"));
+        send_ok(&r, Method::Delete, "/graph/other", "");
+    }
+
+    fn filter_check_json(rocket: &Rocket, url: &'static str, body: &'static str) -> Value {
+        let mut req = MockRequest::new(Method::Post, url)
+            .header(ContentType::JSON)
+            .body(body);
+        let mut response = req.dispatch_with(&rocket);
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.body().and_then(|b| b.into_string()).unwrap();
+        serde_json::from_str(body.as_str()).unwrap()
+    }
+
+    #[test]
+    fn firewall_check() {
+        let r = rocket_init();
+        let res = filter_check_json(&r, "/firewall/check", r#"{"filter": "src host 10::1 and tcp port 22"}"#);
+        assert_eq!(res["valid"], json!(true));
+        assert_eq!(res["error"], Value::Null);
+        assert_eq!(res["bpf"], Value::Null);
+        let res = filter_check_json(&r, "/firewall/check?bpf=true", r#"{"filter": "tcp"}"#);
+        assert_eq!(res["valid"], json!(true));
+        let bpf = res["bpf"].as_array().unwrap();
+        assert!(!bpf.is_empty());
+        assert!(bpf[0]["code"].is_u64() && bpf[0]["k"].is_u64());
+        let res = filter_check_json(&r, "/firewall/check", r#"{"filter": "not a filter", "side": "east"}"#);
+        assert_eq!(res["valid"], json!(false));
+        assert!(res["error"].is_string());
+        send_status(&r, Method::Post, "/firewall/check", r#"{"filter": "tcp", "side": "north"}"#,
+                    Status::BadRequest);
+    }
+
+    #[test]
+    fn vhost() {
+        let r = rocket_init();
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "vhostgraph"}"#);
+        send_ok(&r, Method::Post, "/graph/vhostgraph/brick", r#"{"type": "vhost", "name": "vm1"}"#);
+        send_ok(&r, Method::Post, "/graph/vhostgraph/brick",
+                r#"{"type": "switch", "name": "switch1", "west_ports": 1, "east_ports": 1, "side": "west"}"#);
+        send_ok(&r, Method::Post, "/graph/vhostgraph/brick", r#"{"type": "vhost", "name": "vm2"}"#);
+        send_ok(&r, Method::Post, "/graph/vhostgraph/link", r#"{"west": "vm1", "east": "switch1"}"#);
+        send_ok(&r, Method::Post, "/graph/vhostgraph/link", r#"{"west": "switch1", "east": "vm2"}"#);
+        let vm1 = request_json(&r, "/graph/vhostgraph/brick/vm1");
+        assert_eq!(vm1["type_name"], json!("vhost"));
+        assert!(vm1["socket_path"].as_str().unwrap().starts_with("/tmp"));
+        assert_eq!(vm1["east"], json!(["switch1"]));
+        send_status(&r, Method::Post, "/graph/vhostgraph/brick",
+                    r#"{"type": "vhost", "name": "vm3", "socket_dir": "/var/tmp"}"#, Status::Conflict);
+        send_ok(&r, Method::Delete, "/graph/vhostgraph/link/vm1/switch1", "");
+        send_ok(&r, Method::Delete, "/graph/vhostgraph/brick/vm2", "");
+        let switch = request_json(&r, "/graph/vhostgraph/brick/switch1");
+        assert_eq!(switch["west"], json!([]));
+        assert_eq!(switch["east"], json!([]));
+        send_ok(&r, Method::Delete, "/graph/vhostgraph", "");
+    }
+
+    #[test]
+    fn vtep() {
+        let r = rocket_init();
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "vtepgraph"}"#);
+        send_ok(&r, Method::Post, "/graph/vtepgraph/brick",
+                r#"{"type": "vtep", "name": "vtep1", "side": "east", "ports": 2, "ip": "10.0.0.1",
+                    "mac": "52:54:00:00:00:01"}"#);
+        send_status(&r, Method::Post, "/graph/vtepgraph/brick",
+                    r#"{"type": "vtep", "name": "vtep2", "side": "east", "ports": 2, "ip": "10.0.0.1",
+                        "mac": "52:54:00:00"}"#, Status::BadRequest);
+        send_ok(&r, Method::Post, "/graph/vtepgraph/brick", r#"{"type": "nop", "name": "vm1"}"#);
+        send_ok(&r, Method::Post, "/graph/vtepgraph/brick", r#"{"type": "nop", "name": "vm2"}"#);
+        send_ok(&r, Method::Post, "/graph/vtepgraph/brick", r#"{"type": "nop", "name": "net"}"#);
+        send_ok(&r, Method::Post, "/graph/vtepgraph/link", r#"{"west": "vm1", "east": "vtep1"}"#);
+        send_ok(&r, Method::Post, "/graph/vtepgraph/link", r#"{"west": "vtep1", "east": "net"}"#);
+        send_status(&r, Method::Post, "/graph/vtepgraph/brick/vtep1/vtep/vnis",
+                    r#"{"vni": 1, "brick": "vm2", "multicast_ip": "239.0.0.1"}"#, Status::BadRequest);
+        send_ok(&r, Method::Post, "/graph/vtepgraph/link", r#"{"west": "vm2", "east": "vtep1"}"#);
+        send_status(&r, Method::Post, "/graph/vtepgraph/brick/vtep1/vtep/vnis",
+                    r#"{"vni": 1, "brick": "vm1", "multicast_ip": "10.0.0.2"}"#, Status::BadRequest);
+        send_ok(&r, Method::Post, "/graph/vtepgraph/brick/vtep1/vtep/vnis",
+                r#"{"vni": 1, "brick": "vm1", "multicast_ip": "239.0.0.1"}"#);
+        send_status(&r, Method::Post, "/graph/vtepgraph/brick/vtep1/vtep/vnis",
+                    r#"{"vni": 1, "brick": "vm2", "multicast_ip": "239.0.0.1"}"#, Status::Conflict);
+        send_ok(&r, Method::Post, "/graph/vtepgraph/brick/vtep1/vtep/vnis",
+                r#"{"vni": 2, "brick": "vm2", "multicast_ip": "239.0.0.2"}"#);
+        send_ok(&r, Method::Post, "/graph/vtepgraph/brick/vtep1/vtep/vni/1/macs",
+                r#"{"mac": "52:54:00:00:00:0A"}"#);
+        send_status(&r, Method::Post, "/graph/vtepgraph/brick/vtep1/vtep/vni/1/macs",
+                    r#"{"mac": "52:54:00:00:00:0a"}"#, Status::Conflict);
+        let vnis = request_json(&r, "/graph/vtepgraph/brick/vtep1/vtep/vnis");
+        assert_eq!(vnis, json!([{"vni": 1, "brick": "vm1", "multicast_ip": "239.0.0.1", "macs": ["52:54:00:00:00:0a"]},
+                                {"vni": 2, "brick": "vm2", "multicast_ip": "239.0.0.2", "macs": []}]));
+        // Bound neighbours stay linked until their VNI is removed.
+        send_status(&r, Method::Delete, "/graph/vtepgraph/brick/vm1", "", Status::Conflict);
+        send_status(&r, Method::Delete, "/graph/vtepgraph/link/vm2/vtep1", "", Status::Conflict);
+        send_status(&r, Method::Delete, "/graph/vtepgraph/brick/vtep1/link", "", Status::Conflict);
+        send_ok(&r, Method::Delete, "/graph/vtepgraph/brick/vtep1/vtep/vni/1/mac/52:54:00:00:00:0a", "");
+        send_ok(&r, Method::Delete, "/graph/vtepgraph/brick/vtep1/vtep/vni/2", "");
+        let vnis = request_json(&r, "/graph/vtepgraph/brick/vtep1/vtep/vnis");
+        assert_eq!(vnis, json!([{"vni": 1, "brick": "vm1", "multicast_ip": "239.0.0.1", "macs": []}]));
+        let vtep = request_json(&r, "/graph/vtepgraph/brick/vtep1");
+        assert_eq!(vtep["west"], json!(["vm1", "vm2"]));
+        assert_eq!(vtep["east"], json!(["net"]));
+        send_ok(&r, Method::Delete, "/graph/vtepgraph/link/vm2/vtep1", "");
+        send_ok(&r, Method::Post, "/graph/vtepgraph/brick/vtep1/rename", r#"{"name": "vtep2"}"#);
+        let vnis = request_json(&r, "/graph/vtepgraph/brick/vtep2/vtep/vnis");
+        assert_eq!(vnis[0]["brick"], json!("vm1"));
+        send_status(&r, Method::Get, "/graph/vtepgraph/brick/net/vtep/vnis", "", Status::BadRequest);
+        send_ok(&r, Method::Delete, "/graph/vtepgraph", "");
+    }
+
+    #[test]
+    fn antispoof() {
+        let r = rocket_init();
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "spoofgraph"}"#);
+        send_ok(&r, Method::Post, "/graph/spoofgraph/brick",
+                r#"{"type": "antispoof", "name": "as1", "side": "east", "mac": "52:54:00:00:00:01",
+                    "arp": true, "ipv4": ["10.0.0.1"]}"#);
+        send_ok(&r, Method::Post, "/graph/spoofgraph/brick", r#"{"type": "nop", "name": "vm"}"#);
+        send_ok(&r, Method::Post, "/graph/spoofgraph/brick", r#"{"type": "nop", "name": "net"}"#);
+        send_ok(&r, Method::Post, "/graph/spoofgraph/link", r#"{"west": "vm", "east": "as1"}"#);
+        send_ok(&r, Method::Post, "/graph/spoofgraph/link", r#"{"west": "as1", "east": "net"}"#);
+        send_ok(&r, Method::Post, "/graph/spoofgraph/brick/as1/antispoof/ipv4", r#"{"ip": "10.0.0.2"}"#);
+        send_status(&r, Method::Post, "/graph/spoofgraph/brick/as1/antispoof/ipv4",
+                    r#"{"ip": "10.0.0.2"}"#, Status::Conflict);
+        send_status(&r, Method::Post, "/graph/spoofgraph/brick/as1/antispoof/ipv6",
+                    r#"{"ip": "10.0.0.2"}"#, Status::BadRequest);
+        send_ok(&r, Method::Post, "/graph/spoofgraph/brick/as1/antispoof/ipv6", r#"{"ip": "fe80::1"}"#);
+        send_ok(&r, Method::Put, "/graph/spoofgraph/brick/as1/antispoof/checks", r#"{"ndp": true}"#);
+        send_ok(&r, Method::Put, "/graph/spoofgraph/brick/as1/antispoof/mac", r#"{"mac": "52:54:00:00:00:02"}"#);
+        let as1 = request_json(&r, "/graph/spoofgraph/brick/as1");
+        assert_eq!(as1["west"], json!(["vm"]));
+        assert_eq!(as1["east"], json!(["net"]));
+        assert_eq!(as1["antispoof"],
+                   json!({"name": "as1", "side": "east", "mac": "52:54:00:00:00:02", "arp": true, "ndp": true,
+                          "ipv4": ["10.0.0.1", "10.0.0.2"], "ipv6": ["fe80::1"]}));
+        send_ok(&r, Method::Delete, "/graph/spoofgraph/brick/as1/antispoof/ipv4", "");
+        send_ok(&r, Method::Put, "/graph/spoofgraph/brick/as1/antispoof/checks", r#"{"arp": false}"#);
+        let settings = request_json(&r, "/graph/spoofgraph/brick/as1/antispoof");
+        assert_eq!(settings["ipv4"], json!([]));
+        assert_eq!(settings["arp"], json!(false));
+        assert_eq!(settings["ipv6"], json!(["fe80::1"]));
+        send_status(&r, Method::Get, "/graph/spoofgraph/brick/vm/antispoof", "", Status::BadRequest);
+        send_ok(&r, Method::Delete, "/graph/spoofgraph", "");
+    }
+
+    #[test]
+    fn queue() {
+        let r = rocket_init();
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "queuegraph1"}"#);
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "queuegraph2"}"#);
+        send_ok(&r, Method::Post, "/graph/queuegraph1/brick", r#"{"type": "queue", "name": "q1"}"#);
+        send_ok(&r, Method::Post, "/graph/queuegraph1/brick", r#"{"type": "nop", "name": "nop1"}"#);
+        send_ok(&r, Method::Post, "/graph/queuegraph2/brick", r#"{"type": "queue", "name": "q2", "size": 64}"#);
+        send_ok(&r, Method::Post, "/graph/queuegraph1/link", r#"{"west": "nop1", "east": "q1"}"#);
+        send_status(&r, Method::Post, "/graph/queuegraph1/brick/nop1/queue/friend",
+                    r#"{"graph": "queuegraph2", "brick": "q2"}"#, Status::BadRequest);
+        send_status(&r, Method::Post, "/graph/queuegraph1/brick/q1/queue/friend",
+                    r#"{"graph": "queuegraph1", "brick": "q1"}"#, Status::BadRequest);
+        send_ok(&r, Method::Post, "/graph/queuegraph1/brick/q1/queue/friend",
+                r#"{"graph": "queuegraph2", "brick": "q2"}"#);
+        send_status(&r, Method::Post, "/graph/queuegraph1/brick/q1/queue/friend",
+                    r#"{"graph": "queuegraph2", "brick": "q2"}"#, Status::Conflict);
+        let q2 = request_json(&r, "/graph/queuegraph2/brick/q2");
+        assert_eq!(q2["queue_friend"], json!({"graph": "queuegraph1", "brick": "q1"}));
+        send_status(&r, Method::Delete, "/graph/queuegraph2/brick/q2", "", Status::Conflict);
+        send_status(&r, Method::Post, "/graph/queuegraph1/brick/q1/rename", r#"{"name": "q3"}"#, Status::Conflict);
+        send_status(&r, Method::Post, "/graph/queuegraph2/rename", r#"{"name": "other"}"#, Status::Conflict);
+        send_status(&r, Method::Delete, "/graph/queuegraph2", "", Status::Conflict);
+        send_ok(&r, Method::Delete, "/graph/queuegraph2/brick/q2/queue/friend", "");
+        let q1 = request_json(&r, "/graph/queuegraph1/brick/q1");
+        assert_eq!(q1["queue_friend"], json!(null));
+        send_ok(&r, Method::Delete, "/graph/queuegraph2", "");
+        send_ok(&r, Method::Delete, "/graph/queuegraph1", "");
+    }
+
+    #[test]
+    fn capture() {
+        let r = rocket_init();
+        // Files left by an interrupted run would block the captures.
+        let _ = ::std::fs::remove_dir_all("target/captures/capturegraph");
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "capturegraph"}"#);
+        send_ok(&r, Method::Post, "/graph/capturegraph/brick", r#"{"type": "nop", "name": "a"}"#);
+        send_ok(&r, Method::Post, "/graph/capturegraph/brick", r#"{"type": "nop", "name": "b"}"#);
+        send_ok(&r, Method::Post, "/graph/capturegraph/link", r#"{"west": "a", "east": "b"}"#);
+        send_status(&r, Method::Post, "/graph/capturegraph/capture",
+                    r#"{"name": "cap1", "west": "a", "east": "b", "filter": "not a filter"}"#, Status::BadRequest);
+        send_status(&r, Method::Post, "/graph/capturegraph/capture", r#"{"name": "cap1", "west": "a"}"#,
+                    Status::BadRequest);
+        send_status(&r, Method::Post, "/graph/capturegraph/capture", r#"{"name": "cap1", "west": "b", "east": "a"}"#,
+                    Status::NotFound);
+        send_status(&r, Method::Post, "/graph/capturegraph/capture", r#"{"name": "..", "west": "a", "east": "b"}"#,
+                    Status::BadRequest);
+        send_ok(&r, Method::Post, "/graph/capturegraph/capture",
+                r#"{"name": "cap1", "west": "a", "east": "b", "filter": "icmp", "max_packets": 10}"#);
+        let cap = request_json(&r, "/graph/capturegraph/capture/cap1");
+        assert_eq!(cap["path"], json!("target/captures/capturegraph/cap1.pcap"));
+        let a = request_json(&r, "/graph/capturegraph/brick/a");
+        assert_eq!(a["east"], json!(["cap1"]));
+        send_ok(&r, Method::Post, "/graph/capturegraph/capture", r#"{"name": "cap2", "brick": "b", "side": "east"}"#);
+        let captures = request_json(&r, "/graph/capturegraph/capture");
+        assert_eq!(captures[0]["state"], json!("running"));
+        assert_eq!(captures[1]["name"], json!("cap2"));
+        // Captures are not exported, the link they are inserted in is.
+        let state = request_json(&r, "/graph/capturegraph/export");
+        assert_eq!(state["links"], json!([{"west": "a", "east": "b", "west_port": 0, "east_port": 0}]));
+        send_status(&r, Method::Delete, "/graph/capturegraph/brick/a", "", Status::Conflict);
+        send_status(&r, Method::Delete, "/graph/capturegraph/brick/cap1", "", Status::Conflict);
+        send_status(&r, Method::Delete, "/graph/capturegraph/link/a/cap1", "", Status::Conflict);
+        send_ok(&r, Method::Post, "/graph/capturegraph/capture/cap1/stop", "");
+        let cap = request_json(&r, "/graph/capturegraph/capture/cap1");
+        assert_eq!(cap["state"], json!("stopped"));
+        let b = request_json(&r, "/graph/capturegraph/brick/b");
+        assert_eq!(b["west"], json!(["a"]));
+        request_ok(&r, "/graph/capturegraph/capture/cap1/pcap");
+        send_ok(&r, Method::Delete, "/graph/capturegraph/capture/cap1", "");
+        send_ok(&r, Method::Delete, "/graph/capturegraph/capture/cap2", "");
+        send_status(&r, Method::Get, "/graph/capturegraph/capture/cap1", "", Status::NotFound);
+        send_ok(&r, Method::Delete, "/graph/capturegraph", "");
+    }
+
+    #[test]
+    fn stream() {
+        let r = rocket_init();
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "streamgraph"}"#);
+        send_ok(&r, Method::Post, "/graph/streamgraph/brick", r#"{"type": "nop", "name": "a"}"#);
+        send_status(&r, Method::Get, "/graph/streamgraph/brick/a/stream?side=up", "", Status::BadRequest);
+        send_status(&r, Method::Get, "/graph/streamgraph/brick/b/stream?side=east", "", Status::NotFound);
+        {
+            let mut req = MockRequest::new(Method::Get, "/graph/streamgraph/brick/a/stream?side=east&duration=1");
+            let mut response = req.dispatch_with(&r);
+            assert_eq!(response.status(), Status::Ok);
+            let captures = request_json(&r, "/graph/streamgraph/capture");
+            assert_eq!(captures[0]["name"], json!("stream1"));
+            let body = response.body().and_then(|b| b.into_string()).unwrap();
+            assert_eq!(body, "");
+        }
+        // The capture ends with the stream.
+        let captures = request_json(&r, "/graph/streamgraph/capture");
+        assert_eq!(captures, json!([]));
+        // A linked side is streamed in its link.
+        send_ok(&r, Method::Post, "/graph/streamgraph/brick", r#"{"type": "nop", "name": "b"}"#);
+        send_ok(&r, Method::Post, "/graph/streamgraph/link", r#"{"west": "a", "east": "b"}"#);
+        {
+            let mut req = MockRequest::new(Method::Get, "/graph/streamgraph/brick/a/stream?side=east&duration=1");
+            let mut response = req.dispatch_with(&r);
+            assert_eq!(response.status(), Status::Ok);
+            let b = request_json(&r, "/graph/streamgraph/brick/b");
+            assert_eq!(b["west"], json!(["stream1"]));
+            let body = response.body().and_then(|b| b.into_string()).unwrap();
+            assert_eq!(body, "");
+        }
+        let b = request_json(&r, "/graph/streamgraph/brick/b");
+        assert_eq!(b["west"], json!(["a"]));
+        send_ok(&r, Method::Delete, "/graph/streamgraph", "");
+    }
+
+    #[test]
+    fn topology() {
+        let r = rocket_init();
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "mygraph"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "tap", "name": "tap1"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "tap", "name": "tap2"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick",
+                r#"{"type": "hub", "name": "hub1", "west_ports": 2, "east_ports": 1}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "tap1", "east": "hub1"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "tap2", "east": "hub1"}"#);
+        let hub = request_json(&r, "/graph/mygraph/brick/hub1");
+        assert_eq!(hub["west_ports"], json!(2));
+        assert_eq!(hub["east_ports"], json!(1));
+        assert_eq!(hub["west"], json!(["tap1", "tap2"]));
+        assert_eq!(hub["east"], json!([]));
+        let graph = request_json(&r, "/graph/mygraph");
+        assert_eq!(graph["links"],
+                   json!([{"west": "tap1", "east": "hub1", "west_port": 0, "east_port": 0},
+                          {"west": "tap2", "east": "hub1", "west_port": 0, "east_port": 1}]));
+        assert_eq!(hub["ports"],
+                   json!([{"side": "west", "port": 0, "peer": "tap1", "peer_port": 0},
+                          {"side": "west", "port": 1, "peer": "tap2", "peer_port": 0},
+                          {"side": "east", "port": 0, "peer": null, "peer_port": null}]));
+        request_ok(&r, "/graph/mygraph/brick/hub1/stats");
+        let stats = request_json(&r, "/graph/mygraph/stats");
+        assert_eq!(stats["bricks"].as_object().map(|b| b.len()), Some(3));
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "my\"graph"}"#);
+        let metrics = request_string(&r, "/metrics");
+        assert!(metrics.contains(r#"rpg_bricks{graph="my\"graph"} 0"#));
+        send_ok(&r, Method::Delete, "/graph/mygraph/link/tap1/hub1", "");
+        let hub = request_json(&r, "/graph/mygraph/brick/hub1");
+        assert_eq!(hub["west"], json!(["tap2"]));
+        send_status(&r, Method::Post, "/graph/mygraph/link",
+                    r#"{"west": "tap1", "east": "hub1", "east_port": 2}"#, Status::BadRequest);
+        send_status(&r, Method::Post, "/graph/mygraph/link",
+                    r#"{"west": "tap1", "east": "hub1", "east_port": 1}"#, Status::Conflict);
+        send_ok(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "tap1", "east": "hub1", "east_port": 0}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "tap", "name": "tap3"}"#);
+        send_status(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "tap3", "east": "hub1"}"#,
+                    Status::Conflict);
+        // Grow hub1 to link tap3, links are kept.
+        let mut req = MockRequest::new(Method::Put, "/graph/mygraph/brick/hub1/ports")
+            .header(ContentType::JSON)
+            .body(r#"{"west_ports": 3}"#);
+        let mut response = req.dispatch_with(&r);
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.body().and_then(|b| b.into_string()).unwrap();
+        let resized: Value = serde_json::from_str(body.as_str()).unwrap();
+        assert_eq!(resized["ports"].as_array().map(|p| p.len()), Some(4));
+        let hub = request_json(&r, "/graph/mygraph/brick/hub1");
+        assert_eq!(hub["west_ports"], json!(3));
+        assert_eq!(hub["east_ports"], json!(1));
+        assert_eq!(hub["west"].as_array().map(|w| w.len()), Some(2));
+        send_ok(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "tap3", "east": "hub1"}"#);
+        send_status(&r, Method::Put, "/graph/mygraph/brick/hub1/ports", r#"{"west_ports": 2}"#,
+                    Status::Conflict);
+        send_status(&r, Method::Put, "/graph/mygraph/brick/tap1/ports", r#"{"west_ports": 2}"#,
+                    Status::BadRequest);
+        // hub1 has 3 links on its west side, a nop cannot take them.
+        send_status(&r, Method::Put, "/graph/mygraph/brick/hub1", r#"{"type": "nop", "name": "nop1"}"#,
+                    Status::BadRequest);
+        let hub = request_json(&r, "/graph/mygraph/brick/hub1");
+        assert_eq!(hub["west"].as_array().map(|w| w.len()), Some(3));
+        send_ok(&r, Method::Put, "/graph/mygraph/brick/hub1",
+                r#"{"type": "switch", "name": "switch1", "west_ports": 3, "east_ports": 1, "side": "east"}"#);
+        send_status(&r, Method::Get, "/graph/mygraph/brick/hub1", "", Status::NotFound);
+        let switch = request_json(&r, "/graph/mygraph/brick/switch1");
+        assert_eq!(switch["type_name"], json!("switch"));
+        assert_eq!(switch["west"].as_array().map(|w| w.len()), Some(3));
+        // A linked switch is only resized if it may forget learned MAC addresses.
+        send_status(&r, Method::Put, "/graph/mygraph/brick/switch1/ports", r#"{"east_ports": 2}"#,
+                    Status::Conflict);
+        send_ok(&r, Method::Put, "/graph/mygraph/brick/switch1/ports?flush=true", r#"{"east_ports": 2}"#);
+        assert_eq!(request_json(&r, "/graph/mygraph/brick/switch1")["east_ports"], json!(2));
+        send_ok(&r, Method::Delete, "/graph/mygraph", "");
+    }
+
+    #[test]
+    fn errors() {
+        let r = rocket_init();
+        send_status(&r, Method::Get, "/graph/mygraph", "", Status::NotFound);
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "mygraph"}"#);
+        send_status(&r, Method::Post, "/graph", r#"{"name": "mygraph"}"#, Status::Conflict);
+        send_status(&r, Method::Get, "/graph/mygraph/brick/tap1", "", Status::NotFound);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "tap", "name": "tap1"}"#);
+        send_status(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "tap", "name": "tap1"}"#, Status::Conflict);
+        send_status(&r, Method::Post, "/graph/mygraph/brick",
+                    r#"{"type": "switch", "name": "switch1", "west_ports": 1, "east_ports": 1, "side": "north"}"#,
+                    Status::BadRequest);
+        send_status(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "tap1", "east": "tap2"}"#, Status::NotFound);
+        send_status(&r, Method::Post, "/graph/mygraph/brick/tap1/firewall/rules",
+                    r#"{"side": "west", "filter": "src host 10::1"}"#, Status::BadRequest);
+        send_ok(&r, Method::Delete, "/graph/mygraph", "");
+        send_status(&r, Method::Delete, "/graph/mygraph", "", Status::NotFound);
+    }
+
+    #[test]
+    fn state_replay() {
+        pg::init();
+        let graphs = Arc::new(RwLock::new(HashMap::<String, Arc<GraphRuntime>>::new()));
+        let name = String::from("mygraph");
+        let brick = |t: &str, n: &str| {
+            serde_json::from_str::<BrickCreation>(format!(r#"{{"type": "{}", "name": "{}"}}"#, t, n).as_str()).unwrap()
+        };
+        let link = |w: &str, e: &str| {
+            LinkCreation { west: String::from(w), east: String::from(e), west_port: None, east_port: None }
+        };
+        assert!(graph_create(&graphs, GraphCreation { name: name.clone(), core: None }).is_ok());
+        assert!(brick_create(&graphs, name.clone(), brick("tap", "tap1")).is_ok());
+        assert!(brick_create(&graphs, name.clone(), brick("firewall", "fw")).is_ok());
+        assert!(brick_create(&graphs, name.clone(), brick("tap", "tap2")).is_ok());
+        assert!(link_create(&graphs, name.clone(), link("tap1", "fw")).is_ok());
+        assert!(link_create(&graphs, name.clone(), link("fw", "tap2")).is_ok());
+        let rule = FirewallRuleEntry { id: 0, filter: String::from("src host 10::1"), side: String::from("west") };
+        assert!(firewall_rule_create(&graphs, name.clone(), String::from("fw"), rule).is_ok());
+
+        let g = graph_find(&graphs, &name).unwrap();
+        let saved = g.exec(|g| Ok(serde_json::to_string(&state::GraphState::new(g)).unwrap())).unwrap();
+        assert!(graph_remove(&graphs, &name).is_ok());
+        assert!(state::apply(&graphs, serde_json::from_str(saved.as_str()).unwrap()).is_ok());
+
+        let g = graph_find(&graphs, &name).unwrap();
+        let counts = g.exec(|g| {
+            let rules = g.firewall_rules.get("fw").map(|r| (r.rules.len(), r.applied.len()));
+            Ok((g.graph.bricks.len(), g.links.len(), rules))
+        }).unwrap();
+        // The rule was never reloaded and must stay pending.
+        assert_eq!(counts, (3, 2, Some((1, 0))));
+        assert!(graph_remove(&graphs, &name).is_ok());
+    }
+
+    #[test]
+    fn import_export() {
+        let r = rocket_init();
+        send_ok(&r, Method::Put, "/graph/mygraph", r#"{
+            "bricks": [
+                {"type": "tap", "name": "tap1"},
+                {"type": "switch", "name": "switch1", "west_ports": 2, "east_ports": 2, "side": "west"},
+                {"type": "firewall", "name": "fw"},
+                {"type": "tap", "name": "tap2"}
+            ],
+            "links": [
+                {"west": "tap1", "east": "switch1"},
+                {"west": "switch1", "east": "fw"},
+                {"west": "fw", "east": "tap2"}
+            ],
+            "firewall_rules": {
+                "fw": [{"side": "west", "filter": "src host 10::1"}]
+            }
+        }"#);
+        request_ok(&r, "/graph/mygraph/brick/switch1");
+        request_ok(&r, "/graph/mygraph/export");
+        // A link to a missing brick must not create anything.
+        send_status(&r, Method::Put, "/graph/other", r#"{
+            "bricks": [{"type": "nop", "name": "nop1"}],
+            "links": [{"west": "nop1", "east": "nop2"}]
+        }"#, Status::NotFound);
+        send_status(&r, Method::Get, "/graph/other", "", Status::NotFound);
+        // The replaced graph is destroyed first so its taps can be built again.
+        send_ok(&r, Method::Put, "/graph/mygraph", r#"{
+            "bricks": [{"type": "tap", "name": "tap1"}, {"type": "tap", "name": "tap2"}],
+            "links": [{"west": "tap1", "east": "tap2"}]
+        }"#);
+        send_status(&r, Method::Get, "/graph/mygraph/brick/switch1", "", Status::NotFound);
+        // The replaced graph is built again when the import fails.
+        send_status(&r, Method::Put, "/graph/mygraph", r#"{
+            "bricks": [{"type": "tap", "name": "tap3"}],
+            "links": [{"west": "tap3", "east": "tap4"}]
+        }"#, Status::NotFound);
+        let tap1 = request_json(&r, "/graph/mygraph/brick/tap1");
+        assert_eq!(tap1["east"], json!(["tap2"]));
+        send_ok(&r, Method::Delete, "/graph/mygraph", "");
+
+        // Port 0 of the hub is free once tap1 is unlinked, tap2 stays on port 1.
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "mygraph"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "tap", "name": "tap1"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "tap", "name": "tap2"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick",
+                r#"{"type": "hub", "name": "hub1", "west_ports": 1, "east_ports": 2}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "hub1", "east": "tap1"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "hub1", "east": "tap2"}"#);
+        send_ok(&r, Method::Delete, "/graph/mygraph/link/hub1/tap1", "");
+        let exported = request_json(&r, "/graph/mygraph/export");
+        assert_eq!(exported["links"], json!([{"west": "hub1", "east": "tap2", "west_port": 1, "east_port": 0}]));
+        let mut req = MockRequest::new(Method::Put, "/graph/other")
+            .header(ContentType::JSON)
+            .body(exported.to_string());
+        assert_eq!(req.dispatch_with(&r).status(), Status::Ok);
+        let hub = request_json(&r, "/graph/other/brick/hub1");
+        assert_eq!(hub["east"], json!(["tap2"]));
+        send_ok(&r, Method::Delete, "/graph/other", "");
+        send_ok(&r, Method::Delete, "/graph/mygraph", "");
+    }
+
+    #[test]
+    fn run_state() {
+        let r = rocket_init();
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "mygraph"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "nop", "name": "nop1"}"#);
+        assert_eq!(request_json(&r, "/graph/mygraph")["state"], json!("running"));
+        send_ok(&r, Method::Post, "/graph/mygraph/stop", "");
+        assert_eq!(request_json(&r, "/graph/mygraph")["state"], json!("stopped"));
+        // Configuration is still applied while the graph is stopped.
+        send_ok(&r, Method::Post, "/graph/mygraph/brick", r#"{"type": "nop", "name": "nop2"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/link", r#"{"west": "nop1", "east": "nop2"}"#);
+        send_ok(&r, Method::Post, "/graph/mygraph/pause", r#"{"duration_ms": 60000}"#);
+        assert_eq!(request_json(&r, "/graph/mygraph")["state"], json!("paused"));
+        send_status(&r, Method::Post, "/graph/mygraph/pause", r#"{"duration_ms": 18446744073709551615}"#,
+                    Status::BadRequest);
+        send_ok(&r, Method::Post, "/graph/mygraph/start", "");
+        assert_eq!(request_json(&r, "/graph/mygraph")["state"], json!("running"));
+        send_status(&r, Method::Post, "/graph/other/start", "", Status::NotFound);
+        send_ok(&r, Method::Delete, "/graph/mygraph", "");
+    }
+
+    #[test]
+    fn command_panic() {
+        pg::init();
+        let graphs = Arc::new(RwLock::new(HashMap::<String, Arc<GraphRuntime>>::new()));
+        let name = String::from("panicgraph");
+        assert!(graph_create(&graphs, GraphCreation { name: name.clone(), core: None }).is_ok());
+        let g = graph_find(&graphs, &name).unwrap();
+        let ret: ApiResult<()> = g.exec(|_| panic!("command panic"));
+        match ret {
+            Err(ApiError::PgError(_)) => {}
+            _ => panic!("command panic not reported"),
+        }
+        // The poll thread keeps running.
+        assert_eq!(g.exec(|g| Ok(g.brick_names().len())).ok(), Some(0));
+        assert!(graph_remove(&graphs, &name).is_ok());
+    }
+
+    #[test]
+    fn core_pinning() {
+        let r = rocket_init();
+        send_ok(&r, Method::Post, "/graph", r#"{"name": "pinned", "core": 0}"#);
+        assert_eq!(request_json(&r, "/graph/pinned")["core"], json!(0));
+        send_status(&r, Method::Post, "/graph", r#"{"name": "other", "core": 0}"#, Status::Conflict);
+        send_status(&r, Method::Post, "/graph", r#"{"name": "other", "core": 100000}"#,
+                    Status::BadRequest);
+        send_ok(&r, Method::Delete, "/graph/pinned", "");
+
+        assert_eq!(cores::dpdk_cores("-c1 -n1 --no-huge"), Some(vec![0]));
+        assert_eq!(cores::dpdk_cores("-c 0x6"), Some(vec![1, 2]));
+        assert_eq!(cores::dpdk_cores("-l 0,2-4"), Some(vec![0, 2, 3, 4]));
+        assert_eq!(cores::dpdk_cores("--lcores 0,1@3,(4-5)@(6,7)"), Some(vec![0, 3, 6, 7]));
+        assert_eq!(cores::dpdk_cores("-c1 -n1 --lcores 0,1 -l 0,1"), Some(vec![0, 1]));
+        assert_eq!(cores::dpdk_cores("-n1 --no-huge"), None);
+    }
+
+    #[test]
+    fn legacy() {
+        let r = legacy::mount(rocket_init());
+        request_ok(&r, "/graph/new?name=mygraph");
+        request_ok(&r, "/graph/mygraph/brick/new/tap?name=tap1");
+        request_ok(&r, "/graph/mygraph/brick/new/firewall?name=fw");
         request_ok(&r, "/graph/mygraph/brick/link?west=tap1&east=fw");
-        request_ok(&r, "/graph/mygraph/brick/link?west=fw&east=tap2");
         request_ok(&r, "/graph/mygraph/brick/fw/firewall/rule?side=west&filter=src%20host%2010%3A%3A1");
-        request_ok(&r, "/graph/mygraph/brick/fw/firewall/flush");
-        request_ok(&r, "/graph/mygraph/brick/fw/firewall/rule?side=west&filter=src%20host%2010%3A%3A1");
-        request_ok(&r, "/graph/mygraph/brick/fw/firewall/rule?side=west&filter=src%20host%2010%3A%3A2");
         request_ok(&r, "/graph/mygraph/brick/fw/firewall/reload");
+        request_ok(&r, "/graph/mygraph/brick/unlink?west=tap1&east=fw");
+        request_ok(&r, "/graph/mygraph/brick/tap1/delete");
+        request_ok(&r, "/graph/mygraph/delete");
     }
 }
@@ -0,0 +1,262 @@
+/* Copyright 2017 Outscale SAS
+ *
+ * This file is part of RPG - Remote PacketGraph.
+ *
+ * Pg is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 3 as published
+ * by the Free Software Foundation.
+ *
+ * Packetgraph is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Packetgraph.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+// Prometheus exporter, see
+// https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use rocket::{State, Route};
+use rocket::http::ContentType;
+use rocket::response::{self, Responder};
+use rocket::response::content::Content;
+use pg::Side;
+use super::{GraphMap, RpgGraph, BrickCreation};
+
+/// Count API requests by route and HTTP status.
+#[derive(Clone)]
+pub struct Metrics {
+    requests: Arc<Mutex<HashMap<(&'static str, u16), u64>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics { requests: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Run a route handler and count the request once its response is built.
+    pub fn track<R, F: FnOnce() -> R>(&self, route: &'static str, handler: F) -> Tracked<R> {
+        Tracked {
+            metrics: self.clone(),
+            route: route,
+            inner: handler(),
+        }
+    }
+
+    fn request(&self, route: &'static str, status: u16) {
+        let mut requests = self.requests.lock().unwrap();
+        *requests.entry((route, status)).or_insert(0) += 1;
+    }
+}
+
+pub struct Tracked<R> {
+    metrics: Metrics,
+    route: &'static str,
+    inner: R,
+}
+
+impl<'r, R: Responder<'r>> Responder<'r> for Tracked<R> {
+    fn respond(self) -> response::Result<'r> {
+        let res = self.inner.respond();
+        let status = match res {
+            Ok(ref r) => r.status().code,
+            Err(ref s) => s.code,
+        };
+        self.metrics.request(self.route, status);
+        res
+    }
+}
+
+/// Time spent in the poll loop of a graph.
+#[derive(Default)]
+pub struct PollStats {
+    pub iterations: u64,
+    pub duration: Duration,
+}
+
+fn seconds(d: &Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+// Escape a label value as the exposition format requires.
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// pg has no firewall statistics: packets dropped by a firewall coming from one
+// side are the packets it received on this side minus the packets the next
+// brick received from it. This is only known when the next brick has no other
+// link on this side.
+fn firewall_dropped(g: &RpgGraph, counts: &HashMap<String, (u64, u64)>, fw: &str, east_side: bool) -> Option<u64> {
+    let next: Vec<&String> = g.links
+        .iter()
+        .filter_map(|l| match east_side {
+            false if l.west == fw => Some(&l.east),
+            true if l.east == fw => Some(&l.west),
+            _ => None,
+        })
+        .collect();
+    if next.len() != 1 {
+        return None;
+    }
+    let next = next[0];
+    let next_links = g.links
+        .iter()
+        .filter(|l| if east_side { l.west == *next } else { l.east == *next })
+        .count();
+    if next_links != 1 {
+        return None;
+    }
+    match (counts.get(fw), counts.get(next)) {
+        (Some(&(fw_west, _)), Some(&(next_west, _))) if !east_side => Some(fw_west.saturating_sub(next_west)),
+        (Some(&(_, fw_east)), Some(&(_, next_east))) if east_side => Some(fw_east.saturating_sub(next_east)),
+        _ => None,
+    }
+}
+
+// Packets received on the west and east sides of each brick.
+fn pkts_counts(g: &mut RpgGraph) -> HashMap<String, (u64, u64)> {
+    let mut counts = HashMap::new();
+    for brick_name in g.brick_names() {
+        if let (Some(west), Some(east)) = (g.pkts_count(&brick_name, Side::West),
+                                           g.pkts_count(&brick_name, Side::East)) {
+            counts.insert(brick_name, (west, east));
+        }
+    }
+    counts
+}
+
+/// Packets dropped by firewall `fw` coming from its west and east sides, when
+/// they can be derived.
+pub(crate) fn firewall_drops(g: &mut RpgGraph, fw: &str) -> (Option<u64>, Option<u64>) {
+    let counts = pkts_counts(g);
+    (firewall_dropped(g, &counts, fw, false), firewall_dropped(g, &counts, fw, true))
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn render(graphs: &GraphMap, metrics: &Metrics) -> String {
+    let mut out = String::new();
+    let map = graphs.read().unwrap();
+
+    header(&mut out, "rpg_graphs", "gauge", "Number of graphs.");
+    let _ = writeln!(out, "rpg_graphs {}", map.len());
+
+    let mut bricks = String::new();
+    let mut packets = String::new();
+    let mut dropped = String::new();
+    let mut iterations = String::new();
+    let mut duration = String::new();
+    for (name, g) in map.iter() {
+        let name = label(name);
+        let ret = g.exec(move |g| {
+            let mut b = String::new();
+            let mut p = String::new();
+            let mut f = String::new();
+            let mut i = String::new();
+            let mut d = String::new();
+            let _ = writeln!(b, "rpg_bricks{{graph=\"{}\"}} {}", name, g.brick_names().len());
+            let _ = writeln!(i,
+                             "rpg_poll_iterations_total{{graph=\"{}\"}} {}",
+                             name,
+                             g.poll_stats.iterations);
+            let _ = writeln!(d,
+                             "rpg_poll_duration_seconds_total{{graph=\"{}\"}} {}",
+                             name,
+                             seconds(&g.poll_stats.duration));
+            let counts = pkts_counts(g);
+            for (brick_name, &(west, east)) in counts.iter() {
+                let _ = writeln!(p,
+                                 "rpg_brick_packets_total{{graph=\"{}\",brick=\"{}\",side=\"west\"}} {}",
+                                 name,
+                                 label(brick_name),
+                                 west);
+                let _ = writeln!(p,
+                                 "rpg_brick_packets_total{{graph=\"{}\",brick=\"{}\",side=\"east\"}} {}",
+                                 name,
+                                 label(brick_name),
+                                 east);
+            }
+            for (brick_name, spec) in g.specs.iter() {
+                if let BrickCreation::Firewall(_) = *spec {
+                    for &(east_side, side) in [(false, "west"), (true, "east")].iter() {
+                        if let Some(n) = firewall_dropped(g, &counts, brick_name, east_side) {
+                            let _ = writeln!(f,
+                                             "rpg_firewall_dropped_packets_total{{graph=\"{}\",brick=\"{}\",side=\"{}\"}} {}",
+                                             name,
+                                             label(brick_name),
+                                             side,
+                                             n);
+                        }
+                    }
+                }
+            }
+            Ok((b, p, f, i, d))
+        });
+        // Skip a graph whose poll thread has stopped.
+        let (b, p, f, i, d) = match ret {
+            Ok(m) => m,
+            Err(_) => continue,
+        };
+        bricks.push_str(b.as_str());
+        packets.push_str(p.as_str());
+        dropped.push_str(f.as_str());
+        iterations.push_str(i.as_str());
+        duration.push_str(d.as_str());
+    }
+    header(&mut out, "rpg_bricks", "gauge", "Number of bricks per graph.");
+    out.push_str(bricks.as_str());
+    header(&mut out,
+           "rpg_brick_packets_total",
+           "counter",
+           "Packets received by a brick on one side.");
+    out.push_str(packets.as_str());
+    header(&mut out,
+           "rpg_firewall_dropped_packets_total",
+           "counter",
+           "Packets a firewall received on one side and did not forward, only \
+            given when the next brick has no other link on that side.");
+    out.push_str(dropped.as_str());
+    header(&mut out,
+           "rpg_poll_iterations_total",
+           "counter",
+           "Number of poll loop iterations per graph.");
+    out.push_str(iterations.as_str());
+    header(&mut out,
+           "rpg_poll_duration_seconds_total",
+           "counter",
+           "Time spent polling bricks per graph.");
+    out.push_str(duration.as_str());
+
+    header(&mut out,
+           "rpg_http_requests_total",
+           "counter",
+           "API requests by route and status.");
+    let requests = metrics.requests.lock().unwrap();
+    for (&(route, status), count) in requests.iter() {
+        let _ = writeln!(out,
+                         "rpg_http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                         label(route),
+                         status,
+                         count);
+    }
+    out
+}
+
+#[get("/metrics")]
+fn metrics(graphs: State<GraphMap>, metrics: State<Metrics>) -> Content<String> {
+    Content(ContentType::Plain, render(&graphs, &metrics))
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![metrics]
+}
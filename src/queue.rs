@@ -0,0 +1,163 @@
+/* Copyright 2017 Outscale SAS
+ *
+ * This file is part of RPG - Remote PacketGraph.
+ *
+ * Pg is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 3 as published
+ * by the Free Software Foundation.
+ *
+ * Packetgraph is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Packetgraph.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+// Friended queue bricks move packets between graphs polled by different
+// threads. Both graphs keep track of the friendship so neither queue can be
+// destroyed, and neither graph renamed, while the other one uses it.
+
+use rocket::{State, Route};
+use rocket_contrib::{JSON, Value};
+use super::*;
+use error::{ApiError, ApiResult};
+use metrics::{Metrics, Tracked};
+use state;
+
+/// Queue brick of a graph.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct QueueFriend {
+    pub graph: String,
+    pub brick: String,
+}
+
+fn queue_find<'a>(g: &'a RpgGraph, brick_name: &str) -> ApiResult<&'a SysBrick> {
+    match (g.specs.get(brick_name), g.sys.get(brick_name)) {
+        (Some(&BrickCreation::Queue(_)), Some(b)) => Ok(b),
+        (Some(_), _) => Err(ApiError::InvalidArgument(format!("brick {} is not a queue", brick_name))),
+        (None, _) => Err(ApiError::NotFound(format!("brick {} not found", brick_name))),
+    }
+}
+
+/// Refuse to destroy a friended queue.
+pub(crate) fn unfriended_check(g: &RpgGraph, brick_name: &str) -> ApiResult<()> {
+    match g.queue_friends.get(brick_name) {
+        Some(f) => {
+            Err(ApiError::Conflict(format!("queue {} is friend with queue {} of graph {}",
+                                           brick_name,
+                                           f.brick,
+                                           f.graph)))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Refuse to remove or rename a graph having friended queues.
+pub(crate) fn graph_check(g: &RpgGraph) -> ApiResult<()> {
+    for name in g.queue_friends.keys() {
+        unfriended_check(g, name)?;
+    }
+    Ok(())
+}
+
+/// Friend a queue with a queue of the same or of another graph.
+pub(crate) fn friend_create(graphs: &GraphMap,
+                            graph_name: String,
+                            brick_name: String,
+                            friend: QueueFriend)
+                            -> ApiResult<()> {
+    if graph_name == friend.graph && brick_name == friend.brick {
+        return Err(ApiError::InvalidArgument(String::from("cannot friend a queue with itself")));
+    }
+    // Keep graphs from being removed or renamed meanwhile.
+    let map = graphs.write().unwrap();
+    let g = match map.get(&graph_name) {
+        Some(g) => g.clone(),
+        None => return Err(ApiError::NotFound(format!("graph {} not found", graph_name))),
+    };
+    let friend_graph = match map.get(&friend.graph) {
+        Some(g) => g.clone(),
+        None => return Err(ApiError::NotFound(format!("graph {} not found", friend.graph))),
+    };
+    // Record the friendship in the friend graph first so the friend queue
+    // cannot be destroyed before it is friended.
+    let me = QueueFriend {
+        graph: graph_name.clone(),
+        brick: brick_name.clone(),
+    };
+    let friend_brick = friend.brick.clone();
+    let shared = friend_graph.exec(move |g| {
+        let shared = queue_find(g, &friend_brick)?.shared();
+        unfriended_check(g, &friend_brick)?;
+        g.queue_friends.insert(friend_brick, me);
+        Ok(shared)
+    })?;
+    let friend_brick = friend.brick.clone();
+    let ret = g.exec(move |g| {
+        unfriended_check(g, &brick_name)?;
+        sys::queue_friend(queue_find(g, &brick_name)?, shared).map_err(ApiError::PgError)?;
+        g.queue_friends.insert(brick_name, friend);
+        Ok(())
+    });
+    if ret.is_err() {
+        let _ = friend_graph.exec(move |g| {
+            g.queue_friends.remove(&friend_brick);
+            Ok(())
+        });
+    }
+    ret
+}
+
+#[post("/graph/<graph_name>/brick/<brick_name>/queue/friend", format = "application/json", data = "<friend>")]
+fn queue_friend(graphs: State<GraphMap>,
+                metrics: State<Metrics>,
+                graph_name: String,
+                brick_name: String,
+                friend: JSON<QueueFriend>)
+                -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("queue_friend", || {
+        let ret = friend_create(&graphs, graph_name, brick_name, friend.into_inner()).map(|_| success());
+        state::commit(&graphs, ret)
+    })
+}
+
+#[delete("/graph/<graph_name>/brick/<brick_name>/queue/friend")]
+fn queue_unfriend(graphs: State<GraphMap>,
+                  metrics: State<Metrics>,
+                  graph_name: String,
+                  brick_name: String)
+                  -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("queue_unfriend", || {
+        state::commit(&graphs, friend_remove(&graphs, graph_name, brick_name))
+    })
+}
+
+fn friend_remove(graphs: &GraphMap, graph_name: String, brick_name: String) -> ApiResult<JSON<Value>> {
+    let map = graphs.write().unwrap();
+    let g = match map.get(&graph_name) {
+        Some(g) => g.clone(),
+        None => return Err(ApiError::NotFound(format!("graph {} not found", graph_name))),
+    };
+    let friend = g.exec(move |g| {
+        let friend = match g.queue_friends.get(&brick_name) {
+            Some(f) => f.clone(),
+            None => return Err(ApiError::NotFound(format!("queue {} has no friend", brick_name))),
+        };
+        sys::queue_unfriend(queue_find(g, &brick_name)?);
+        g.queue_friends.remove(&brick_name);
+        Ok(friend)
+    })?;
+    if let Some(friend_graph) = map.get(&friend.graph) {
+        friend_graph.exec(move |g| {
+            g.queue_friends.remove(&friend.brick);
+            Ok(())
+        })?;
+    }
+    Ok(success())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![queue_friend, queue_unfriend]
+}
@@ -0,0 +1,243 @@
+/* Copyright 2017 Outscale SAS
+ *
+ * This file is part of RPG - Remote PacketGraph.
+ *
+ * Pg is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 3 as published
+ * by the Free Software Foundation.
+ *
+ * Packetgraph is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Packetgraph.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+// Each graph is owned by its poll thread. API calls don't lock the graph: they
+// queue a command which is run by the poll thread between two poll iterations.
+
+use std::cmp::min;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use pg::Side;
+use super::RpgGraph;
+use cores;
+use error::{ApiError, ApiResult};
+
+/// Number of polls between two checks of packet counters while traffic flows.
+const IDLE_CHECK_POLLS: u32 = 1024;
+/// First and maximal wait between two polls when no packet flows.
+const IDLE_MIN_WAIT_NS: u32 = 10_000;
+const IDLE_MAX_WAIT_NS: u32 = 10_000_000;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RunState {
+    Running,
+    Paused(Instant),
+    Stopped,
+}
+
+impl RunState {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RunState::Running => "running",
+            RunState::Paused(_) => "paused",
+            RunState::Stopped => "stopped",
+        }
+    }
+}
+
+fn stopped() -> ApiError {
+    ApiError::PgError(String::from("graph thread has stopped"))
+}
+
+enum Command {
+    Exec(Box<FnMut(&mut RpgGraph) + Send>),
+    // Replied once the graph is destroyed.
+    Quit(Sender<()>),
+}
+
+/// Handle on a graph owned by its poll thread.
+pub struct GraphRuntime {
+    // Sender is not Sync, the mutex is only held while queuing a command.
+    tx: Mutex<Sender<Command>>,
+    /// Core the poll thread is pinned on.
+    pub core: Option<usize>,
+}
+
+impl GraphRuntime {
+    pub(crate) fn spawn(graph: RpgGraph) -> ApiResult<GraphRuntime> {
+        let (tx, rx) = channel();
+        let (pin_tx, pin_rx) = channel();
+        let core = graph.core;
+        thread::spawn(move || {
+            let pinned = match core {
+                Some(c) => cores::pin(c),
+                None => Ok(()),
+            };
+            let run = pinned.is_ok();
+            let _ = pin_tx.send(pinned);
+            if run {
+                pooler(graph, rx);
+            }
+        });
+        match pin_rx.recv() {
+            Ok(Ok(())) => Ok(GraphRuntime { tx: Mutex::new(tx), core: core }),
+            Ok(Err(e)) => Err(ApiError::InvalidArgument(e)),
+            Err(_) => Err(stopped()),
+        }
+    }
+
+    /// Run `f` on the graph between two poll iterations and wait for its
+    /// result, fails if the poll thread has stopped.
+    pub(crate) fn exec<T, F>(&self, f: F) -> ApiResult<T>
+        where T: Send + 'static,
+              F: FnOnce(&mut RpgGraph) -> ApiResult<T> + Send + 'static
+    {
+        let (reply_tx, reply_rx) = channel();
+        let mut f = Some(f);
+        let cmd = Command::Exec(Box::new(move |g: &mut RpgGraph| {
+            if let Some(f) = f.take() {
+                // Keep the poll thread, and the graph, alive if a command panics.
+                let ret = match panic::catch_unwind(AssertUnwindSafe(|| f(g))) {
+                    Ok(ret) => ret,
+                    Err(_) => Err(ApiError::PgError(String::from("graph command has panicked"))),
+                };
+                let _ = reply_tx.send(ret);
+            }
+        }));
+        if self.tx.lock().unwrap().send(cmd).is_err() {
+            return Err(stopped());
+        }
+        match reply_rx.recv() {
+            Ok(ret) => ret,
+            Err(_) => Err(stopped()),
+        }
+    }
+
+    /// Stop the poll thread and wait for the graph to be destroyed, so its
+    /// bricks can be built again.
+    pub fn quit(&self) {
+        let (done_tx, done_rx) = channel();
+        if self.tx.lock().unwrap().send(Command::Quit(done_tx)).is_ok() {
+            let _ = done_rx.recv();
+        }
+    }
+}
+
+fn pkts_total(g: &mut RpgGraph) -> u64 {
+    let mut total = 0;
+    for b in g.graph.bricks.values_mut() {
+        total += b.pkts_count(Side::West) + b.pkts_count(Side::East);
+    }
+    for b in g.sys.values() {
+        total += b.pkts_count(Side::West) + b.pkts_count(Side::East);
+    }
+    total
+}
+
+// Wait more and more between polls while packet counters don't move.
+struct Idle {
+    polls: u32,
+    pkts: u64,
+    wait: Duration,
+}
+
+impl Idle {
+    fn new() -> Idle {
+        Idle {
+            polls: 0,
+            pkts: 0,
+            wait: Duration::new(0, 0),
+        }
+    }
+
+    fn update(&mut self, g: &mut RpgGraph) {
+        self.polls += 1;
+        if self.wait == Duration::new(0, 0) && self.polls < IDLE_CHECK_POLLS {
+            return;
+        }
+        self.polls = 0;
+        let pkts = pkts_total(g);
+        if pkts != self.pkts {
+            self.wait = Duration::new(0, 0);
+        } else if self.wait == Duration::new(0, 0) {
+            self.wait = Duration::new(0, IDLE_MIN_WAIT_NS);
+        } else {
+            self.wait = min(self.wait * 2, Duration::new(0, IDLE_MAX_WAIT_NS));
+        }
+        self.pkts = pkts;
+    }
+}
+
+// None: wait forever, Some(0): don't wait.
+fn next_command(rx: &Receiver<Command>, wait: Option<Duration>) -> Result<Option<Command>, ()> {
+    match wait {
+        None => rx.recv().map(Some).map_err(|_| ()),
+        Some(d) if d == Duration::new(0, 0) => {
+            match rx.try_recv() {
+                Ok(c) => Ok(Some(c)),
+                Err(TryRecvError::Empty) => Ok(None),
+                Err(TryRecvError::Disconnected) => Err(()),
+            }
+        }
+        Some(d) => {
+            match rx.recv_timeout(d) {
+                Ok(c) => Ok(Some(c)),
+                Err(RecvTimeoutError::Timeout) => Ok(None),
+                Err(RecvTimeoutError::Disconnected) => Err(()),
+            }
+        }
+    }
+}
+
+fn pooler(mut g: RpgGraph, rx: Receiver<Command>) {
+    let mut idle = Idle::new();
+    loop {
+        let mut wait = match g.run_state {
+            RunState::Running => Some(idle.wait),
+            RunState::Stopped => None,
+            RunState::Paused(until) => {
+                let now = Instant::now();
+                match until > now {
+                    true => Some(until - now),
+                    false => Some(Duration::new(0, 0)),
+                }
+            }
+        };
+        // Apply all queued commands before polling again.
+        loop {
+            match next_command(&rx, wait) {
+                Err(_) => return,
+                Ok(Some(Command::Quit(done))) => {
+                    drop(g);
+                    let _ = done.send(());
+                    return;
+                }
+                Ok(Some(Command::Exec(mut f))) => f(&mut g),
+                Ok(None) => break,
+            }
+            wait = Some(Duration::new(0, 0));
+        }
+        match g.run_state {
+            RunState::Running => {
+                let start = Instant::now();
+                g.poll();
+                g.poll_stats.iterations += 1;
+                g.poll_stats.duration += start.elapsed();
+                idle.update(&mut g);
+            }
+            RunState::Paused(until) => {
+                if Instant::now() >= until {
+                    g.run_state = RunState::Running;
+                }
+            }
+            RunState::Stopped => {}
+        }
+    }
+}
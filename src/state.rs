@@ -0,0 +1,300 @@
+/* Copyright 2017 Outscale SAS
+ *
+ * This file is part of RPG - Remote PacketGraph.
+ *
+ * Pg is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 3 as published
+ * by the Free Software Foundation.
+ *
+ * Packetgraph is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Packetgraph.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+// Graph topology is saved in a JSON file (`state_file` in Rocket.toml) after
+// each successful modification and replayed when rpg starts.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use rocket::config;
+use serde_json;
+use super::{GraphMap, RpgGraph, BrickCreation, LinkCreation, FirewallRuleEntry, FirewallRules,
+            GraphCreation, graph_create, graph_remove, graph_find, brick_create, link_create,
+            firewall_find, firewall_restore};
+use capture;
+use cores;
+use runtime::GraphRuntime;
+use queue::{self, QueueFriend};
+use vtep::{self, VtepVni};
+use error::{ApiError, ApiResult};
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct GraphState {
+    #[serde(default)]
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) core: Option<usize>,
+    #[serde(default)]
+    pub(crate) bricks: Vec<BrickCreation>,
+    #[serde(default)]
+    pub(crate) links: Vec<LinkCreation>,
+    #[serde(default)]
+    pub(crate) firewall_rules: HashMap<String, Vec<FirewallRuleEntry>>,
+    // Rules loaded by the last reload of each firewall, states saved before
+    // this field existed had all their rules loaded.
+    #[serde(default)]
+    pub(crate) applied_firewall_rules: HashMap<String, Vec<FirewallRuleEntry>>,
+    #[serde(default)]
+    pub(crate) vteps: HashMap<String, Vec<VtepVni>>,
+    // Friends are only restored at startup, once every graph exists.
+    #[serde(default)]
+    pub(crate) queue_friends: HashMap<String, QueueFriend>,
+}
+
+impl GraphState {
+    pub(crate) fn new(g: &RpgGraph) -> GraphState {
+        GraphState {
+            name: g.graph.name.clone(),
+            core: g.core,
+            bricks: g.specs.values().cloned().collect(),
+            // Captures are not saved.
+            links: capture::saved_links(g),
+            firewall_rules: g.firewall_rules
+                .iter()
+                .map(|(brick, rules)| (brick.clone(), rules.rules.clone()))
+                .collect(),
+            applied_firewall_rules: g.firewall_rules
+                .iter()
+                .map(|(brick, rules)| (brick.clone(), rules.applied.clone()))
+                .collect(),
+            vteps: g.vteps.clone(),
+            queue_friends: g.queue_friends.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct RpgState {
+    graphs: Vec<GraphState>,
+}
+
+fn path() -> Option<String> {
+    match config::active() {
+        Some(c) => c.get_str("state_file").ok().map(String::from),
+        None => None,
+    }
+}
+
+fn save(graphs: &GraphMap, path: &str) -> Result<(), String> {
+    // Keep the map locked while writing so concurrent saves don't interleave.
+    let map = graphs.write().unwrap();
+    let mut state = RpgState { graphs: Vec::new() };
+    for (name, g) in map.iter() {
+        // Fail rather than save a state missing this graph.
+        let graph = g.exec(|g| Ok(GraphState::new(g))).map_err(|e| format!("graph {}: {:?}", name, e))?;
+        state.graphs.push(graph);
+    }
+    let data = serde_json::to_string_pretty(&state).map_err(|e| format!("{}", e))?;
+    let tmp = format!("{}.tmp", path);
+    let mut f = File::create(&tmp).map_err(|e| format!("{}", e))?;
+    f.write_all(data.as_bytes()).map_err(|e| format!("{}", e))?;
+    fs::rename(&tmp, path).map_err(|e| format!("{}", e))
+}
+
+/// Save graphs state if the modification succeeded, a failing save is
+/// returned as an error although the modification is kept.
+pub(crate) fn commit<T>(graphs: &GraphMap, res: ApiResult<T>) -> ApiResult<T> {
+    if res.is_ok() {
+        if let Some(path) = path() {
+            save(graphs, path.as_str())
+                .map_err(|e| ApiError::PgError(format!("modification done but state not saved in {}: {}", path, e)))?;
+        }
+    }
+    res
+}
+
+// Rebuild one graph with the same calls as the API.
+pub(crate) fn apply(graphs: &GraphMap, state: GraphState) -> ApiResult<()> {
+    graph_create(graphs, GraphCreation { name: state.name.clone(), core: state.core })?;
+    for brick in state.bricks {
+        brick_create(graphs, state.name.clone(), brick)?;
+    }
+    // Packetgraph always links on the first free port so saved ports cannot
+    // be asked for again once a lower port has been freed: links are done in
+    // saved order and get new ports.
+    for mut link in state.links {
+        link.west_port = None;
+        link.east_port = None;
+        link_create(graphs, state.name.clone(), link)?;
+    }
+    let mut applied = state.applied_firewall_rules;
+    for (brick, rules) in state.firewall_rules {
+        let applied = match applied.remove(&brick) {
+            Some(a) => a,
+            None => rules.clone(),
+        };
+        firewall_load(graphs, state.name.clone(), brick, rules, applied)?;
+    }
+    // VNIs are bound to neighbours once they are linked.
+    for (brick, vnis) in state.vteps {
+        vtep::load(graphs, state.name.clone(), brick, vnis)?;
+    }
+    Ok(())
+}
+
+// Give an id to rules saved without one, ids must be unique in a firewall.
+fn rule_ids(rules: &mut Vec<FirewallRuleEntry>, last_id: &mut u32) -> ApiResult<()> {
+    let mut ids = HashSet::new();
+    for rule in rules.iter_mut() {
+        if rule.id == 0 {
+            *last_id += 1;
+            rule.id = *last_id;
+        }
+        if !ids.insert(rule.id) {
+            return Err(ApiError::Conflict(format!("rule {} already exists", rule.id)));
+        }
+    }
+    Ok(())
+}
+
+// Load applied rules in a firewall then add pending ones without reload, as
+// they were when saved.
+fn firewall_load(graphs: &GraphMap,
+                 graph_name: String,
+                 brick_name: String,
+                 mut rules: Vec<FirewallRuleEntry>,
+                 mut applied: Vec<FirewallRuleEntry>)
+                 -> ApiResult<()> {
+    let mut last_id = rules.iter().chain(applied.iter()).map(|r| r.id).max().unwrap_or(0);
+    // Rules without id are the same in both lists for old states.
+    let same = rules == applied;
+    rule_ids(&mut rules, &mut last_id)?;
+    if same {
+        applied = rules.clone();
+    } else {
+        rule_ids(&mut applied, &mut last_id)?;
+    }
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        firewall_find(g, &brick_name)?;
+        g.firewall_rules.insert(brick_name.clone(),
+                                FirewallRules {
+                                    last_id: last_id,
+                                    rules: rules,
+                                    applied: applied,
+                                });
+        firewall_restore(g, &brick_name)
+    })
+}
+
+// Build a graph aside from `map`, it is only returned once every brick, link
+// and firewall rule has been applied.
+fn build(map: &HashMap<String, Arc<GraphRuntime>>,
+         graph_name: &str,
+         mut state: GraphState)
+         -> ApiResult<Arc<GraphRuntime>> {
+    // Check the core against other graphs as the staging map is empty.
+    state.core = cores::assign(map, graph_name, state.core)?;
+    let staging: GraphMap = Arc::new(RwLock::new(HashMap::new()));
+    if let Err(e) = apply(&staging, state) {
+        let _ = graph_remove(&staging, graph_name);
+        return Err(e);
+    }
+    let g = staging.write().unwrap().remove(graph_name).unwrap();
+    Ok(g)
+}
+
+/// Build a whole graph and only publish it once every brick, link and
+/// firewall rule has been applied. An existing graph with the same name is
+/// replaced unless some of its queues are friended. Queue friends are not
+/// imported.
+pub(crate) fn import(graphs: &GraphMap, graph_name: String, mut state: GraphState) -> ApiResult<()> {
+    state.name = graph_name.clone();
+    // The map stays locked so the name and the core are not taken meanwhile.
+    let mut map = graphs.write().unwrap();
+    let old_state = match map.get(&graph_name) {
+        Some(old) => {
+            match old.exec(|g| {
+                queue::graph_check(g)?;
+                Ok(GraphState::new(g))
+            }) {
+                Ok(s) => Some(s),
+                Err(e @ ApiError::Conflict(_)) => return Err(e),
+                // A graph whose thread has stopped is only removed.
+                Err(_) => None,
+            }
+        }
+        None => None,
+    };
+    // Bricks of the old graph may hold what the new one needs (nic ports,
+    // vhost sockets, tap interfaces): it is destroyed first and built again if
+    // the new graph cannot be built.
+    if let Some(old) = map.remove(&graph_name) {
+        old.quit();
+    }
+    let err = match build(&map, &graph_name, state) {
+        Ok(g) => {
+            map.insert(graph_name, g);
+            return Ok(());
+        }
+        Err(e) => e,
+    };
+    if let Some(old_state) = old_state {
+        match build(&map, &graph_name, old_state) {
+            Ok(g) => {
+                map.insert(graph_name, g);
+            }
+            Err(e) => {
+                return Err(ApiError::PgError(format!("{}, graph {} cannot be built again and is removed: {}",
+                                                     err.description(),
+                                                     graph_name,
+                                                     e.description())))
+            }
+        }
+    }
+    Err(err)
+}
+
+fn load(path: &str) -> Result<RpgState, String> {
+    let mut f = File::open(path).map_err(|e| format!("{}", e))?;
+    let mut data = String::new();
+    f.read_to_string(&mut data).map_err(|e| format!("{}", e))?;
+    serde_json::from_str(data.as_str()).map_err(|e| format!("{}", e))
+}
+
+/// Restore graphs saved in `state_file`, if any.
+pub(crate) fn restore(graphs: &GraphMap) -> Result<(), String> {
+    let path = match path() {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    if !Path::new(&path).exists() {
+        return Ok(());
+    }
+    let state = load(path.as_str()).map_err(|e| format!("cannot restore state from {}: {}", path, e))?;
+    let mut friends = Vec::new();
+    for g in state.graphs {
+        let name = g.name.clone();
+        for (brick, friend) in g.queue_friends.iter() {
+            // Both queues of a pair list each other, friend them once.
+            if (&name, brick) < (&friend.graph, &friend.brick) {
+                friends.push((name.clone(), brick.clone(), friend.clone()));
+            }
+        }
+        // Build aside so a graph failing half way is not kept, the next save
+        // would replace the saved graph with it.
+        import(graphs, name.clone(), g)
+            .map_err(|e| format!("cannot restore graph {}: {}", name, e.description()))?;
+    }
+    for (graph, brick, friend) in friends {
+        queue::friend_create(graphs, graph.clone(), brick.clone(), friend)
+            .map_err(|e| format!("cannot restore friend of queue {} in graph {}: {}", brick, graph, e.description()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,286 @@
+/* Copyright 2017 Outscale SAS
+ *
+ * This file is part of RPG - Remote PacketGraph.
+ *
+ * Pg is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 3 as published
+ * by the Free Software Foundation.
+ *
+ * Packetgraph is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Packetgraph.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+// Live packet streams. A capture without file is started on a side of a brick
+// and a summary of each packet it keeps is sent as a JSON line in a chunked
+// HTTP response. The capture ends with the response, once the client has gone
+// or the capture limits are reached. Capture bricks see packets of both
+// directions without telling which one, so summaries have no direction.
+
+use std::cmp::min;
+use std::io::{self, Read};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::time::{Duration, Instant};
+use rocket::{State, Route};
+use rocket::http::ContentType;
+use rocket::response::Stream;
+use rocket::response::content::Content;
+use serde_json;
+use super::*;
+use capture::{self, CaptureCreation, CaptureOutput, Packet};
+use error::{ApiError, ApiResult};
+use metrics::{Metrics, Tracked};
+use runtime::GraphRuntime;
+
+/// Summaries waiting for a slow client, more are dropped.
+const PENDING_MAX: usize = 1024;
+/// Seconds without packet before blank lines are sent to the client.
+const KEEPALIVE_SECS: u64 = 10;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+#[derive(FromForm)]
+struct StreamParams {
+    side: String,
+    filter: Option<String>,
+    // Packets per second, more are dropped.
+    rate: Option<u32>,
+    max_packets: Option<u64>,
+    // Seconds.
+    duration: Option<u64>,
+}
+
+#[derive(Serialize, Default)]
+struct PacketSummary {
+    // Seconds since epoch.
+    time: f64,
+    len: u32,
+    ethertype: u16,
+    src_mac: String,
+    dst_mac: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vlan: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    src_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dst_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protocol: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    src_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dst_port: Option<u16>,
+}
+
+fn u16_get(b: &[u8]) -> u16 {
+    (b[0] as u16) << 8 | b[1] as u16
+}
+
+fn mac_str(b: &[u8]) -> String {
+    format!("{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", b[0], b[1], b[2], b[3], b[4], b[5])
+}
+
+fn ipv4_str(b: &[u8]) -> String {
+    format!("{}", Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+}
+
+fn ipv6_str(b: &[u8]) -> String {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&b[..16]);
+    format!("{}", Ipv6Addr::from(octets))
+}
+
+// Decode ethernet, VLAN, ARP, IPv4, IPv6, TCP and UDP headers as far as the
+// captured data goes. IPv6 extension headers are not followed.
+fn summary(packet: &Packet) -> PacketSummary {
+    let d = packet.data;
+    let mut s = PacketSummary {
+        time: packet.ts_sec as f64 + packet.ts_usec as f64 / 1e6,
+        len: packet.len,
+        ..PacketSummary::default()
+    };
+    if d.len() < 14 {
+        return s;
+    }
+    s.dst_mac = mac_str(&d[0..6]);
+    s.src_mac = mac_str(&d[6..12]);
+    let mut ethertype = u16_get(&d[12..]);
+    let mut l3 = 14;
+    if ethertype == ETHERTYPE_VLAN && d.len() >= 18 {
+        s.vlan = Some(u16_get(&d[14..]) & 0x0fff);
+        ethertype = u16_get(&d[16..]);
+        l3 = 18;
+    }
+    s.ethertype = ethertype;
+    let (protocol, l4) = match ethertype {
+        ETHERTYPE_IPV4 if d.len() >= l3 + 20 => {
+            s.src_ip = Some(ipv4_str(&d[l3 + 12..]));
+            s.dst_ip = Some(ipv4_str(&d[l3 + 16..]));
+            (d[l3 + 9], l3 + (d[l3] & 0x0f) as usize * 4)
+        }
+        ETHERTYPE_IPV6 if d.len() >= l3 + 40 => {
+            s.src_ip = Some(ipv6_str(&d[l3 + 8..]));
+            s.dst_ip = Some(ipv6_str(&d[l3 + 24..]));
+            (d[l3 + 6], l3 + 40)
+        }
+        // Sender and target addresses of IPv4 over ethernet.
+        ETHERTYPE_ARP if d.len() >= l3 + 28 => {
+            s.src_ip = Some(ipv4_str(&d[l3 + 14..]));
+            s.dst_ip = Some(ipv4_str(&d[l3 + 24..]));
+            return s;
+        }
+        _ => return s,
+    };
+    s.protocol = Some(protocol);
+    if (protocol == IPPROTO_TCP || protocol == IPPROTO_UDP) && d.len() >= l4 + 4 {
+        s.src_port = Some(u16_get(&d[l4..]));
+        s.dst_port = Some(u16_get(&d[l4 + 2..]));
+    }
+    s
+}
+
+/// JSON lines of packet summaries, the capture ends when dropped.
+pub struct PacketStream {
+    lines: Receiver<String>,
+    deadline: Option<Instant>,
+    line: Vec<u8>,
+    sent: usize,
+    graph: Arc<GraphRuntime>,
+    name: String,
+}
+
+impl Read for PacketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.sent == self.line.len() {
+            let mut wait = Duration::from_secs(KEEPALIVE_SECS);
+            if let Some(d) = self.deadline {
+                let now = Instant::now();
+                if now >= d {
+                    return Ok(0);
+                }
+                wait = min(wait, d - now);
+            }
+            // The capture thread closes the channel once limits are reached.
+            match self.lines.recv_timeout(wait) {
+                Ok(l) => self.line = l.into_bytes(),
+                Err(RecvTimeoutError::Timeout) if self.deadline.map_or(true, |d| Instant::now() < d) => {
+                    // Rocket only sends full chunks: fill this one with blank
+                    // lines so a client which has gone is noticed.
+                    for b in buf.iter_mut() {
+                        *b = b'\n';
+                    }
+                    return Ok(buf.len());
+                }
+                Err(_) => return Ok(0),
+            }
+            self.sent = 0;
+        }
+        let n = min(buf.len(), self.line.len() - self.sent);
+        buf[..n].copy_from_slice(&self.line[self.sent..self.sent + n]);
+        self.sent += n;
+        Ok(n)
+    }
+}
+
+impl Drop for PacketStream {
+    fn drop(&mut self) {
+        let name = self.name.clone();
+        let _ = self.graph.exec(move |g| capture::capture_end(g, &name));
+    }
+}
+
+// Give summaries of up to `rate` packets per second to the stream.
+fn summaries(lines: SyncSender<String>, rate: Option<u32>) -> CaptureOutput {
+    let mut second = Instant::now();
+    let mut count = 0;
+    CaptureOutput::Packets(Box::new(move |packet: &Packet| {
+        if let Some(rate) = rate {
+            if second.elapsed() >= Duration::from_secs(1) {
+                second = Instant::now();
+                count = 0;
+            }
+            if count >= rate {
+                return Ok(());
+            }
+            count += 1;
+        }
+        let mut line = serde_json::to_string(&summary(packet)).map_err(|e| format!("{}", e))?;
+        line.push('\n');
+        match lines.try_send(line) {
+            Ok(()) | Err(TrySendError::Full(_)) => Ok(()),
+            Err(TrySendError::Disconnected(_)) => Err(String::from("stream closed")),
+        }
+    }))
+}
+
+fn stream_start(graphs: &GraphMap,
+                graph_name: String,
+                brick_name: String,
+                params: StreamParams)
+                -> ApiResult<PacketStream> {
+    side_parse(params.side.as_str())?;
+    // A stream holds its worker until it ends.
+    if let Some(c) = config::active() {
+        if c.workers < 2 {
+            return Err(ApiError::Conflict(String::from("streams need more than one Rocket worker")));
+        }
+    }
+    let graph = graph_find(graphs, &graph_name)?;
+    let (tx, rx) = sync_channel(PENDING_MAX);
+    let deadline = params.duration.map(|d| Instant::now() + Duration::from_secs(d));
+    let rate = params.rate;
+    let name = graph.exec(move |g| {
+        let name = (1..)
+            .map(|n| format!("stream{}", n))
+            .find(|n| !g.brick_exists(n) && !g.captures.contains_key(n))
+            .unwrap();
+        let spec = CaptureCreation {
+            name: name.clone(),
+            west: None,
+            east: None,
+            brick: Some(brick_name),
+            side: Some(params.side),
+            filter: params.filter,
+            max_packets: params.max_packets,
+            duration: params.duration,
+        };
+        capture::capture_start(g, spec, None, move || Ok(summaries(tx, rate)))?;
+        Ok(name)
+    })?;
+    Ok(PacketStream {
+        lines: rx,
+        deadline: deadline,
+        line: Vec::new(),
+        sent: 0,
+        graph: graph,
+        name: name,
+    })
+}
+
+#[get("/graph/<graph_name>/brick/<brick_name>/stream?<params>")]
+fn packets_stream(graphs: State<GraphMap>,
+                  metrics: State<Metrics>,
+                  graph_name: String,
+                  brick_name: String,
+                  params: StreamParams)
+                  -> Tracked<ApiResult<Content<Stream<PacketStream>>>> {
+    metrics.track("packets_stream", || {
+        let stream = stream_start(&graphs, graph_name, brick_name, params)?;
+        Ok(Content(ContentType::new("application", "x-ndjson"), Stream::from(stream)))
+    })
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![packets_stream]
+}
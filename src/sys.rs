@@ -0,0 +1,536 @@
+/* Copyright 2017 Outscale SAS
+ *
+ * This file is part of RPG - Remote PacketGraph.
+ *
+ * Pg is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 3 as published
+ * by the Free Software Foundation.
+ *
+ * Packetgraph is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Packetgraph.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+// Bricks pg does not wrap are built with the packetgraph C library pg links
+// with. They are linked to pg bricks through the pg_brick pointer pg gives
+// with Brick::get_brick().
+
+use std::ffi::{CStr, CString};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::fs::File;
+use std::os::unix::io::FromRawFd;
+use std::ptr;
+use std::slice;
+use std::sync::{Once, ONCE_INIT};
+use libc::{self, c_char, c_int, c_uint, c_void, FILE};
+use pg::{Brick, Side};
+
+/// Opaque packetgraph `struct pg_brick`.
+pub enum PgBrick {}
+
+// Only the first field of `struct pg_error` is read.
+#[repr(C)]
+struct PgError {
+    message: *mut c_char,
+}
+
+// struct ether_addr of DPDK.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct EtherAddr {
+    addr_bytes: [u8; 6],
+}
+
+// enum pg_side
+const PG_WEST_SIDE: c_int = 0;
+const PG_EAST_SIDE: c_int = 1;
+
+// enum pg_print_flags
+const PG_PRINT_FLAG_PCAP: c_int = 16;
+const PG_PRINT_FLAG_CLOSE_FILE: c_int = 32;
+
+// struct bpf_program and struct pcap_pkthdr of libpcap.
+#[repr(C)]
+struct BpfProgram {
+    bf_len: c_uint,
+    bf_insns: *mut BpfInsn,
+}
+
+/// Instruction of a compiled BPF filter (struct bpf_insn of libpcap).
+#[repr(C)]
+#[derive(Serialize, Clone, Copy)]
+pub struct BpfInsn {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+#[repr(C)]
+struct PcapPkthdr {
+    ts: libc::timeval,
+    caplen: u32,
+    len: u32,
+}
+
+/// Ethernet link type of pcap files.
+const DLT_EN10MB: c_int = 1;
+const SNAPLEN: c_int = 65535;
+
+#[link(name = "packetgraph")]
+extern "C" {
+    fn pg_error_free(error: *mut PgError);
+    fn pg_brick_link(west: *mut PgBrick, east: *mut PgBrick, errp: *mut *mut PgError) -> c_int;
+    fn pg_brick_unlink(brick: *mut PgBrick, errp: *mut *mut PgError);
+    fn pg_brick_unlink_edge(west: *mut PgBrick, east: *mut PgBrick, errp: *mut *mut PgError) -> c_int;
+    fn pg_brick_destroy(brick: *mut PgBrick);
+    fn pg_brick_poll(brick: *mut PgBrick, count: *mut u16, errp: *mut *mut PgError) -> c_int;
+    fn pg_brick_pkts_count_get(brick: *mut PgBrick, side: c_int) -> u64;
+
+    fn pg_vhost_start(base_dir: *const c_char, errp: *mut *mut PgError) -> c_int;
+    fn pg_vhost_new(name: *const c_char, flags: u64, errp: *mut *mut PgError) -> *mut PgBrick;
+    fn pg_vhost_socket_path(brick: *mut PgBrick) -> *const c_char;
+
+    fn pg_vtep_new(name: *const c_char,
+                   max: u32,
+                   output: c_int,
+                   ip: u32,
+                   mac: EtherAddr,
+                   udp_dst_port: u16,
+                   flags: c_int,
+                   errp: *mut *mut PgError)
+                   -> *mut PgBrick;
+    fn pg_vtep_add_vni(brick: *mut PgBrick,
+                       neighbor: *mut PgBrick,
+                       vni: u32,
+                       multicast_ip: u32,
+                       errp: *mut *mut PgError)
+                       -> c_int;
+    fn pg_vtep_add_mac(brick: *mut PgBrick, vni: u32, mac: *mut EtherAddr, errp: *mut *mut PgError) -> c_int;
+
+    fn pg_antispoof_new(name: *const c_char,
+                        outside: c_int,
+                        mac: *mut EtherAddr,
+                        errp: *mut *mut PgError)
+                        -> *mut PgBrick;
+    fn pg_antispoof_arp_enable(brick: *mut PgBrick);
+    fn pg_antispoof_arp_disable(brick: *mut PgBrick);
+    fn pg_antispoof_arp_add(brick: *mut PgBrick, ip: u32, errp: *mut *mut PgError) -> c_int;
+    fn pg_antispoof_arp_del_all(brick: *mut PgBrick);
+    fn pg_antispoof_ndp_enable(brick: *mut PgBrick, errp: *mut *mut PgError) -> c_int;
+    fn pg_antispoof_ndp_disable(brick: *mut PgBrick);
+    fn pg_antispoof_ndp_add(brick: *mut PgBrick, ip: *mut u8, errp: *mut *mut PgError) -> c_int;
+    fn pg_antispoof_ndp_del_all(brick: *mut PgBrick);
+
+    fn pg_queue_new(name: *const c_char, size: c_int, errp: *mut *mut PgError) -> *mut PgBrick;
+    fn pg_queue_friend(queue_1: *mut PgBrick, queue_2: *mut PgBrick, errp: *mut *mut PgError) -> c_int;
+    fn pg_queue_unfriend(queue: *mut PgBrick);
+
+    fn pg_print_new(name: *const c_char,
+                    output: *mut FILE,
+                    flags: c_int,
+                    type_filter: *mut u16,
+                    errp: *mut *mut PgError)
+                    -> *mut PgBrick;
+}
+
+// packetgraph compiles its firewall filters with libpcap.
+#[link(name = "pcap")]
+extern "C" {
+    fn pcap_compile_nopcap(snaplen: c_int,
+                           linktype: c_int,
+                           program: *mut BpfProgram,
+                           buf: *const c_char,
+                           optimize: c_int,
+                           mask: u32)
+                           -> c_int;
+    fn pcap_offline_filter(program: *const BpfProgram, header: *const PcapPkthdr, pkt: *const u8) -> c_int;
+    fn pcap_freecode(program: *mut BpfProgram);
+}
+
+unsafe fn error_take(error: *mut PgError) -> String {
+    if error.is_null() {
+        return String::from("unknown packetgraph error");
+    }
+    let message = match (*error).message.is_null() {
+        true => String::from("unknown packetgraph error"),
+        false => CStr::from_ptr((*error).message).to_string_lossy().into_owned(),
+    };
+    pg_error_free(error);
+    message
+}
+
+fn ret_check(ret: c_int, error: *mut PgError) -> Result<(), String> {
+    match ret {
+        0 => Ok(()),
+        _ => Err(unsafe { error_take(error) }),
+    }
+}
+
+fn c_string(s: &str) -> Result<CString, String> {
+    CString::new(s).map_err(|_| format!("{} contains a NUL character", s))
+}
+
+fn c_side(side: Side) -> c_int {
+    match side {
+        Side::West => PG_WEST_SIDE,
+        _ => PG_EAST_SIDE,
+    }
+}
+
+/// Packetgraph brick of a pg brick.
+pub fn pg_ptr(brick: &Brick) -> *mut PgBrick {
+    brick.get_brick() as *mut PgBrick
+}
+
+pub fn link(west: *mut PgBrick, east: *mut PgBrick) -> Result<(), String> {
+    let mut error = ptr::null_mut();
+    let ret = unsafe { pg_brick_link(west, east, &mut error) };
+    ret_check(ret, error)
+}
+
+pub fn unlink_edge(west: *mut PgBrick, east: *mut PgBrick) -> Result<(), String> {
+    let mut error = ptr::null_mut();
+    let ret = unsafe { pg_brick_unlink_edge(west, east, &mut error) };
+    ret_check(ret, error)
+}
+
+pub fn unlink(brick: *mut PgBrick) -> Result<(), String> {
+    let mut error = ptr::null_mut();
+    unsafe {
+        pg_brick_unlink(brick, &mut error);
+        match error.is_null() {
+            true => Ok(()),
+            false => Err(error_take(error)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SysKind {
+    Vhost,
+    Vtep,
+    Antispoof,
+    Queue,
+    Capture,
+}
+
+/// Brick built with the packetgraph C library, destroyed when dropped.
+pub struct SysBrick {
+    brick: *mut PgBrick,
+    kind: SysKind,
+}
+
+// A brick is only used by the poll thread owning its graph.
+unsafe impl Send for SysBrick {}
+
+/// Brick given to the poll thread of another graph, to friend queues.
+pub struct SharedBrick(*mut PgBrick);
+
+unsafe impl Send for SharedBrick {}
+
+impl SysBrick {
+    pub fn ptr(&self) -> *mut PgBrick {
+        self.brick
+    }
+
+    pub fn type_str(&self) -> &'static str {
+        match self.kind {
+            SysKind::Vhost => "vhost",
+            SysKind::Vtep => "vtep",
+            SysKind::Antispoof => "antispoof",
+            SysKind::Queue => "queue",
+            SysKind::Capture => "capture",
+        }
+    }
+
+    pub fn shared(&self) -> SharedBrick {
+        SharedBrick(self.brick)
+    }
+
+    /// Poll bricks bringing packets in the graph.
+    pub fn poll(&mut self) {
+        if self.kind != SysKind::Vhost && self.kind != SysKind::Queue {
+            return;
+        }
+        let mut count = 0;
+        let mut error = ptr::null_mut();
+        unsafe {
+            if pg_brick_poll(self.brick, &mut count, &mut error) < 0 {
+                error_take(error);
+            }
+        }
+    }
+
+    pub fn pkts_count(&self, side: Side) -> u64 {
+        unsafe { pg_brick_pkts_count_get(self.brick, c_side(side)) }
+    }
+
+    /// Path of the socket of a vhost-user brick.
+    pub fn socket_path(&self) -> Option<String> {
+        if self.kind != SysKind::Vhost {
+            return None;
+        }
+        unsafe {
+            let path = pg_vhost_socket_path(self.brick);
+            match path.is_null() {
+                true => None,
+                false => Some(CStr::from_ptr(path).to_string_lossy().into_owned()),
+            }
+        }
+    }
+}
+
+impl Drop for SysBrick {
+    fn drop(&mut self) {
+        unsafe { pg_brick_destroy(self.brick) };
+    }
+}
+
+fn brick_new(brick: *mut PgBrick, error: *mut PgError, kind: SysKind) -> Result<SysBrick, String> {
+    match brick.is_null() {
+        true => Err(unsafe { error_take(error) }),
+        false => Ok(SysBrick { brick: brick, kind: kind }),
+    }
+}
+
+static VHOST_INIT: Once = ONCE_INIT;
+static mut VHOST_DIR: Option<Result<String, String>> = None;
+
+// The vhost driver is started once, with the directory of the first vhost
+// brick, and cannot be moved afterwards.
+fn vhost_start(dir: &str) -> Result<(), String> {
+    VHOST_INIT.call_once(|| {
+        let ret = c_string(dir).and_then(|d| {
+            let mut error = ptr::null_mut();
+            match unsafe { pg_vhost_start(d.as_ptr(), &mut error) } {
+                0 => Ok(String::from(dir)),
+                _ => Err(unsafe { error_take(error) }),
+            }
+        });
+        unsafe { VHOST_DIR = Some(ret) };
+    });
+    match unsafe { &VHOST_DIR } {
+        &Some(Ok(ref d)) if d == dir => Ok(()),
+        &Some(Ok(ref d)) => Err(format!("vhost sockets are already created in {}", d)),
+        &Some(Err(ref e)) => Err(format!("cannot start vhost: {}", e)),
+        &None => Err(String::from("cannot start vhost")),
+    }
+}
+
+/// Directory of vhost sockets once the vhost driver is started.
+pub fn vhost_started_dir() -> Option<String> {
+    match unsafe { &VHOST_DIR } {
+        &Some(Ok(ref d)) => Some(d.clone()),
+        _ => None,
+    }
+}
+
+/// Create a vhost-user brick, its socket is created in `dir`.
+pub fn vhost_new(name: &str, dir: &str) -> Result<SysBrick, String> {
+    vhost_start(dir)?;
+    let name = c_string(name)?;
+    let mut error = ptr::null_mut();
+    let brick = unsafe { pg_vhost_new(name.as_ptr(), 0, &mut error) };
+    brick_new(brick, error, SysKind::Vhost)
+}
+
+// Addresses and ports are given to packetgraph in network byte order.
+fn ip_be(ip: Ipv4Addr) -> u32 {
+    u32::from(ip).to_be()
+}
+
+/// Create a VXLAN brick with `max` neighbours, its `output` side is linked to
+/// the physical network.
+pub fn vtep_new(name: &str,
+                max: u32,
+                output: Side,
+                ip: Ipv4Addr,
+                mac: [u8; 6],
+                udp_port: u16)
+                -> Result<SysBrick, String> {
+    let name = c_string(name)?;
+    let mut error = ptr::null_mut();
+    let brick = unsafe {
+        pg_vtep_new(name.as_ptr(),
+                    max,
+                    c_side(output),
+                    ip_be(ip),
+                    EtherAddr { addr_bytes: mac },
+                    udp_port.to_be(),
+                    0,
+                    &mut error)
+    };
+    brick_new(brick, error, SysKind::Vtep)
+}
+
+/// Bind `neighbor`, linked to the vtep, to a VNI using a multicast group.
+pub fn vtep_add_vni(vtep: &SysBrick, neighbor: *mut PgBrick, vni: u32, multicast_ip: Ipv4Addr) -> Result<(), String> {
+    let mut error = ptr::null_mut();
+    let ret = unsafe { pg_vtep_add_vni(vtep.brick, neighbor, vni, ip_be(multicast_ip), &mut error) };
+    ret_check(ret, error)
+}
+
+/// Allow a MAC address on a VNI, its remote IP is learned by the vtep.
+pub fn vtep_add_mac(vtep: &SysBrick, vni: u32, mac: [u8; 6]) -> Result<(), String> {
+    let mut mac = EtherAddr { addr_bytes: mac };
+    let mut error = ptr::null_mut();
+    let ret = unsafe { pg_vtep_add_mac(vtep.brick, vni, &mut mac, &mut error) };
+    ret_check(ret, error)
+}
+
+/// Create an antispoof brick letting only `mac` go out of its `outside` side.
+pub fn antispoof_new(name: &str, outside: Side, mac: [u8; 6]) -> Result<SysBrick, String> {
+    let name = c_string(name)?;
+    let mut mac = EtherAddr { addr_bytes: mac };
+    let mut error = ptr::null_mut();
+    let brick = unsafe { pg_antispoof_new(name.as_ptr(), c_side(outside), &mut mac, &mut error) };
+    brick_new(brick, error, SysKind::Antispoof)
+}
+
+/// Enable or disable ARP checks of an antispoof brick.
+pub fn antispoof_arp(antispoof: &SysBrick, enable: bool) {
+    unsafe {
+        match enable {
+            true => pg_antispoof_arp_enable(antispoof.brick),
+            false => pg_antispoof_arp_disable(antispoof.brick),
+        }
+    }
+}
+
+pub fn antispoof_ipv4_add(antispoof: &SysBrick, ip: Ipv4Addr) -> Result<(), String> {
+    let mut error = ptr::null_mut();
+    let ret = unsafe { pg_antispoof_arp_add(antispoof.brick, ip_be(ip), &mut error) };
+    ret_check(ret, error)
+}
+
+pub fn antispoof_ipv4_flush(antispoof: &SysBrick) {
+    unsafe { pg_antispoof_arp_del_all(antispoof.brick) };
+}
+
+/// Enable or disable NDP checks of an antispoof brick.
+pub fn antispoof_ndp(antispoof: &SysBrick, enable: bool) -> Result<(), String> {
+    if !enable {
+        unsafe { pg_antispoof_ndp_disable(antispoof.brick) };
+        return Ok(());
+    }
+    let mut error = ptr::null_mut();
+    let ret = unsafe { pg_antispoof_ndp_enable(antispoof.brick, &mut error) };
+    ret_check(ret, error)
+}
+
+pub fn antispoof_ipv6_add(antispoof: &SysBrick, ip: Ipv6Addr) -> Result<(), String> {
+    let mut octets = ip.octets();
+    let mut error = ptr::null_mut();
+    let ret = unsafe { pg_antispoof_ndp_add(antispoof.brick, octets.as_mut_ptr(), &mut error) };
+    ret_check(ret, error)
+}
+
+pub fn antispoof_ipv6_flush(antispoof: &SysBrick) {
+    unsafe { pg_antispoof_ndp_del_all(antispoof.brick) };
+}
+
+/// Create a queue brick keeping up to `size` packets, packetgraph picks the
+/// size if 0.
+pub fn queue_new(name: &str, size: u32) -> Result<SysBrick, String> {
+    let name = c_string(name)?;
+    let mut error = ptr::null_mut();
+    let brick = unsafe { pg_queue_new(name.as_ptr(), size as c_int, &mut error) };
+    brick_new(brick, error, SysKind::Queue)
+}
+
+/// Friend two queues: packets entering one go out of the other.
+pub fn queue_friend(queue: &SysBrick, friend: SharedBrick) -> Result<(), String> {
+    let mut error = ptr::null_mut();
+    let ret = unsafe { pg_queue_friend(queue.brick, friend.0, &mut error) };
+    ret_check(ret, error)
+}
+
+/// Unfriend a queue and its friend.
+pub fn queue_unfriend(queue: &SysBrick) {
+    unsafe { pg_queue_unfriend(queue.brick) };
+}
+
+/// Create a brick writing packets going through it in pcap format, the file
+/// is read from the returned pipe and closed when the brick is destroyed.
+pub fn capture_new(name: &str) -> Result<(SysBrick, File), String> {
+    let name = c_string(name)?;
+    let mut fds = [0 as c_int; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(String::from("cannot create pipe"));
+    }
+    let input = unsafe { File::from_raw_fd(fds[0]) };
+    let output = unsafe { libc::fdopen(fds[1], b"w\0".as_ptr() as *const c_char) };
+    if output.is_null() {
+        unsafe { libc::close(fds[1]) };
+        return Err(String::from("cannot open pipe"));
+    }
+    // Packets are read as soon as the brick writes them.
+    unsafe { libc::setvbuf(output, ptr::null_mut(), libc::_IONBF, 0) };
+    let mut error = ptr::null_mut();
+    let brick = unsafe {
+        pg_print_new(name.as_ptr(),
+                     output,
+                     PG_PRINT_FLAG_PCAP | PG_PRINT_FLAG_CLOSE_FILE,
+                     ptr::null_mut(),
+                     &mut error)
+    };
+    if brick.is_null() {
+        unsafe { libc::fclose(output) };
+    }
+    brick_new(brick, error, SysKind::Capture).map(|b| (b, input))
+}
+
+/// BPF filter of ethernet packets.
+pub struct BpfFilter {
+    program: BpfProgram,
+}
+
+// The program is only read once compiled.
+unsafe impl Send for BpfFilter {}
+
+impl BpfFilter {
+    pub fn new(filter: &str) -> Result<BpfFilter, String> {
+        let buf = c_string(filter)?;
+        let mut program = BpfProgram {
+            bf_len: 0,
+            bf_insns: ptr::null_mut(),
+        };
+        match unsafe { pcap_compile_nopcap(SNAPLEN, DLT_EN10MB, &mut program, buf.as_ptr(), 1, 0) } {
+            0 => Ok(BpfFilter { program: program }),
+            _ => Err(format!("cannot compile filter {}", filter)),
+        }
+    }
+
+    /// Tell if a packet of `len` bytes, of which `data` were captured, matches.
+    pub fn matches(&self, data: &[u8], len: u32) -> bool {
+        let header = PcapPkthdr {
+            ts: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            caplen: data.len() as u32,
+            len: len,
+        };
+        unsafe { pcap_offline_filter(&self.program, &header, data.as_ptr()) != 0 }
+    }
+
+    /// Compiled instructions.
+    pub fn instructions(&self) -> Vec<BpfInsn> {
+        if self.program.bf_insns.is_null() {
+            return Vec::new();
+        }
+        let insns = unsafe { slice::from_raw_parts(self.program.bf_insns, self.program.bf_len as usize) };
+        insns.to_vec()
+    }
+}
+
+impl Drop for BpfFilter {
+    fn drop(&mut self) {
+        unsafe { pcap_freecode(&mut self.program) };
+    }
+}
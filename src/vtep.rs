@@ -0,0 +1,309 @@
+/* Copyright 2017 Outscale SAS
+ *
+ * This file is part of RPG - Remote PacketGraph.
+ *
+ * Pg is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License version 3 as published
+ * by the Free Software Foundation.
+ *
+ * Packetgraph is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with Packetgraph.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+// VNIs of VXLAN bricks (vtep). Each neighbour linked on the inner side of a
+// vtep is bound to one VNI with a multicast group, MAC addresses allowed on a
+// VNI can be added. packetgraph learns the remote IP of each MAC and cannot
+// remove a VNI or a MAC: the vtep is built again with the remaining ones.
+
+use rocket::{State, Route};
+use rocket_contrib::{JSON, Value};
+use super::*;
+use error::{ApiError, ApiResult};
+use metrics::{Metrics, Tracked};
+use state;
+
+/// Highest VXLAN network identifier, VNIs have 24 bits.
+const VNI_MAX: u32 = 0xff_ffff;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct VtepVni {
+    vni: u32,
+    // Neighbour brick bound to this VNI.
+    brick: String,
+    multicast_ip: String,
+    #[serde(default)]
+    macs: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct VtepMac {
+    mac: String,
+}
+
+// Output side of a vtep.
+fn vtep_output(g: &RpgGraph, brick_name: &str) -> ApiResult<Side> {
+    match g.specs.get(brick_name) {
+        Some(&BrickCreation::Vtep(ref vtep)) => side_parse(vtep.side.as_str()),
+        Some(_) => Err(ApiError::InvalidArgument(format!("brick {} is not a vtep", brick_name))),
+        None => Err(ApiError::NotFound(format!("brick {} not found", brick_name))),
+    }
+}
+
+fn vni_find(g: &RpgGraph, brick_name: &str, vni: u32) -> ApiResult<usize> {
+    let pos = match g.vteps.get(brick_name) {
+        Some(vnis) => vnis.iter().position(|v| v.vni == vni),
+        None => None,
+    };
+    pos.ok_or_else(|| ApiError::NotFound(format!("vni {} not found", vni)))
+}
+
+// Vtep and VNI a brick is bound to.
+fn binding(g: &RpgGraph, brick_name: &str) -> Option<(String, u32)> {
+    for (vtep, vnis) in g.vteps.iter() {
+        if let Some(v) = vnis.iter().find(|v| v.brick == brick_name) {
+            return Some((vtep.clone(), v.vni));
+        }
+    }
+    None
+}
+
+/// Refuse to remove or rebuild a brick bound to a VNI, the vtep would keep
+/// forwarding to it.
+pub(crate) fn unbound_check(g: &RpgGraph, brick_name: &str) -> ApiResult<()> {
+    match binding(g, brick_name) {
+        Some((vtep, vni)) => {
+            Err(ApiError::Conflict(format!("brick {} is bound to vni {} of vtep {}", brick_name, vni, vtep)))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Refuse to unlink a vtep from a neighbour bound to a VNI.
+pub(crate) fn link_check(g: &RpgGraph, west: &str, east: &str) -> ApiResult<()> {
+    for &(vtep, neighbour) in [(west, east), (east, west)].iter() {
+        match binding(g, neighbour) {
+            Some((ref v, vni)) if v == vtep => {
+                return Err(ApiError::Conflict(format!("brick {} is bound to vni {} of vtep {}", neighbour, vni, vtep)));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Bind a VNI and add its MAC addresses in packetgraph.
+fn vni_apply(g: &RpgGraph, brick_name: &str, vni: &VtepVni) -> ApiResult<()> {
+    let vtep = match g.sys.get(brick_name) {
+        Some(b) => b,
+        None => return Err(ApiError::NotFound(format!("brick {} not found", brick_name))),
+    };
+    let neighbour = match g.brick_ptr(&vni.brick) {
+        Some(b) => b,
+        None => return Err(ApiError::NotFound(format!("brick {} not found", vni.brick))),
+    };
+    let multicast_ip = ipv4_parse(vni.multicast_ip.as_str())?;
+    sys::vtep_add_vni(vtep, neighbour, vni.vni, multicast_ip).map_err(ApiError::PgError)?;
+    for mac in vni.macs.iter() {
+        sys::vtep_add_mac(vtep, vni.vni, mac_parse(mac)?).map_err(ApiError::PgError)?;
+    }
+    Ok(())
+}
+
+/// Bind VNIs known for a vtep on a new vtep brick, once its neighbours are
+/// linked.
+pub(crate) fn restore(g: &RpgGraph, brick_name: &str) -> ApiResult<()> {
+    if let Some(vnis) = g.vteps.get(brick_name) {
+        for vni in vnis.iter() {
+            vni_apply(g, brick_name, vni)?;
+        }
+    }
+    Ok(())
+}
+
+fn vni_add(g: &mut RpgGraph, brick_name: &str, mut vni: VtepVni) -> ApiResult<VtepVni> {
+    let output = vtep_output(g, brick_name)?;
+    if vni.vni > VNI_MAX {
+        return Err(ApiError::InvalidArgument(format!("vni cannot exceed {}", VNI_MAX)));
+    }
+    if !ipv4_parse(vni.multicast_ip.as_str())?.is_multicast() {
+        return Err(ApiError::InvalidArgument(format!("{} is not a multicast address", vni.multicast_ip)));
+    }
+    for mac in vni.macs.iter_mut() {
+        mac_parse(mac)?;
+        *mac = mac.to_lowercase();
+    }
+    if vni_find(g, brick_name, vni.vni).is_ok() {
+        return Err(ApiError::Conflict(format!("vni {} already exists", vni.vni)));
+    }
+    unbound_check(g, &vni.brick)?;
+    let linked = g.links.iter().any(|l| match output {
+        Side::East => l.east == brick_name && l.west == vni.brick,
+        _ => l.west == brick_name && l.east == vni.brick,
+    });
+    if !linked {
+        return Err(ApiError::InvalidArgument(format!("brick {} is not a neighbour of vtep {}", vni.brick, brick_name)));
+    }
+    vni_apply(g, brick_name, &vni)?;
+    g.vteps.entry(String::from(brick_name)).or_insert_with(Vec::new).push(vni.clone());
+    Ok(vni)
+}
+
+// Build a vtep again with other VNIs, as packetgraph cannot remove them.
+fn vtep_rebuild(g: &mut RpgGraph, brick_name: &str, vnis: Vec<VtepVni>) -> ApiResult<()> {
+    let spec = match g.specs.get(brick_name) {
+        Some(s) => s.clone(),
+        None => return Err(ApiError::NotFound(format!("brick {} not found", brick_name))),
+    };
+    brick_rebuild(g, brick_name, spec)?;
+    g.vteps.insert(String::from(brick_name), vnis);
+    restore(g, brick_name)
+}
+
+#[get("/graph/<graph_name>/brick/<brick_name>/vtep/vnis")]
+fn vtep_vnis_get(graphs: State<GraphMap>,
+                 metrics: State<Metrics>,
+                 graph_name: String,
+                 brick_name: String)
+                 -> Tracked<ApiResult<JSON<Vec<VtepVni>>>> {
+    metrics.track("vtep_vnis_get", || {
+        graph_find(&graphs, &graph_name)?.exec(move |g| {
+            vtep_output(g, &brick_name)?;
+            Ok(JSON(g.vteps.get(&brick_name).cloned().unwrap_or_default()))
+        })
+    })
+}
+
+#[post("/graph/<graph_name>/brick/<brick_name>/vtep/vnis", format = "application/json", data = "<vni>")]
+fn vtep_vni_add(graphs: State<GraphMap>,
+                metrics: State<Metrics>,
+                graph_name: String,
+                brick_name: String,
+                vni: JSON<VtepVni>)
+                -> Tracked<ApiResult<JSON<VtepVni>>> {
+    metrics.track("vtep_vni_add", || {
+        let ret = vni_create(&graphs, graph_name, brick_name, vni.into_inner()).map(JSON);
+        state::commit(&graphs, ret)
+    })
+}
+
+fn vni_create(graphs: &GraphMap, graph_name: String, brick_name: String, vni: VtepVni) -> ApiResult<VtepVni> {
+    graph_find(graphs, &graph_name)?.exec(move |g| vni_add(g, &brick_name, vni))
+}
+
+#[delete("/graph/<graph_name>/brick/<brick_name>/vtep/vni/<vni>")]
+fn vtep_vni_delete(graphs: State<GraphMap>,
+                   metrics: State<Metrics>,
+                   graph_name: String,
+                   brick_name: String,
+                   vni: u32)
+                   -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("vtep_vni_delete", || {
+        state::commit(&graphs, vni_remove(&graphs, graph_name, brick_name, vni))
+    })
+}
+
+fn vni_remove(graphs: &GraphMap, graph_name: String, brick_name: String, vni: u32) -> ApiResult<JSON<Value>> {
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        vtep_output(g, &brick_name)?;
+        let pos = vni_find(g, &brick_name, vni)?;
+        let mut vnis = g.vteps[&brick_name].clone();
+        vnis.remove(pos);
+        vtep_rebuild(g, &brick_name, vnis)?;
+        Ok(success())
+    })
+}
+
+#[post("/graph/<graph_name>/brick/<brick_name>/vtep/vni/<vni>/macs",
+       format = "application/json",
+       data = "<mac>")]
+fn vtep_mac_add(graphs: State<GraphMap>,
+                metrics: State<Metrics>,
+                graph_name: String,
+                brick_name: String,
+                vni: u32,
+                mac: JSON<VtepMac>)
+                -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("vtep_mac_add", || {
+        let mac = mac.into_inner().mac;
+        state::commit(&graphs, mac_create(&graphs, graph_name, brick_name, vni, mac))
+    })
+}
+
+fn mac_create(graphs: &GraphMap,
+              graph_name: String,
+              brick_name: String,
+              vni: u32,
+              mac: String)
+              -> ApiResult<JSON<Value>> {
+    let addr = mac_parse(mac.as_str())?;
+    let mac = mac.to_lowercase();
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        vtep_output(g, &brick_name)?;
+        let pos = vni_find(g, &brick_name, vni)?;
+        if g.vteps[&brick_name][pos].macs.contains(&mac) {
+            return Err(ApiError::Conflict(format!("mac {} already exists", mac)));
+        }
+        match g.sys.get(&brick_name) {
+            Some(b) => sys::vtep_add_mac(b, vni, addr).map_err(ApiError::PgError)?,
+            None => return Err(ApiError::NotFound(format!("brick {} not found", brick_name))),
+        }
+        if let Some(vnis) = g.vteps.get_mut(&brick_name) {
+            vnis[pos].macs.push(mac);
+        }
+        Ok(success())
+    })
+}
+
+#[delete("/graph/<graph_name>/brick/<brick_name>/vtep/vni/<vni>/mac/<mac>")]
+fn vtep_mac_delete(graphs: State<GraphMap>,
+                   metrics: State<Metrics>,
+                   graph_name: String,
+                   brick_name: String,
+                   vni: u32,
+                   mac: String)
+                   -> Tracked<ApiResult<JSON<Value>>> {
+    metrics.track("vtep_mac_delete", || {
+        state::commit(&graphs, mac_remove(&graphs, graph_name, brick_name, vni, mac))
+    })
+}
+
+fn mac_remove(graphs: &GraphMap,
+              graph_name: String,
+              brick_name: String,
+              vni: u32,
+              mac: String)
+              -> ApiResult<JSON<Value>> {
+    let mac = mac.to_lowercase();
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        vtep_output(g, &brick_name)?;
+        let pos = vni_find(g, &brick_name, vni)?;
+        let mut vnis = g.vteps[&brick_name].clone();
+        let found = vnis[pos].macs.iter().position(|m| *m == mac);
+        match found {
+            Some(p) => vnis[pos].macs.remove(p),
+            None => return Err(ApiError::NotFound(format!("mac {} not found", mac))),
+        };
+        vtep_rebuild(g, &brick_name, vnis)?;
+        Ok(success())
+    })
+}
+
+/// Bind saved VNIs on a vtep whose neighbours are linked.
+pub(crate) fn load(graphs: &GraphMap, graph_name: String, brick_name: String, vnis: Vec<VtepVni>) -> ApiResult<()> {
+    graph_find(graphs, &graph_name)?.exec(move |g| {
+        vtep_output(g, &brick_name)?;
+        for vni in vnis {
+            vni_add(g, &brick_name, vni)?;
+        }
+        Ok(())
+    })
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![vtep_vnis_get, vtep_vni_add, vtep_vni_delete, vtep_mac_add, vtep_mac_delete]
+}